@@ -0,0 +1,720 @@
+use hmac::{Hmac, KeyInit, Mac};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::http::{HeaderMap, Response};
+use warp::hyper::body::Bytes;
+use warp::hyper::StatusCode;
+use warp::Reply;
+
+use crate::buffer;
+use crate::rate_limit::{self, Limit};
+use crate::sources;
+
+/// Maximum difference between the timestamp of a signed request and the server time
+const MAX_CLOCK_SKEW_SECONDS: i64 = 5 * 60;
+const MIN_NONCE_LENGTH: usize = 8;
+const MAX_BATCH_SOURCES: usize = 64;
+/// The total of a batch is limited by the image bucket of the rate limit
+const MAX_BATCH_COUNT: u64 = u16::MAX as u64;
+const MAX_NONCE_LENGTH: usize = 64;
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 128;
+/// How long the result of a request is kept for retries with the same idempotency key
+const DEFAULT_IDEMPOTENCY_WINDOW_SECONDS: u64 = 24 * 60 * 60;
+/// How long a claimed idempotency key blocks retries if the server stops before its request finished
+const PENDING_SECONDS: u64 = 30;
+/// Most nonces and idempotency keys kept in memory while Redis is unavailable
+const MAX_LOCAL_KEYS: usize = 100_000;
+
+/// Headers of a signed request
+const TIMESTAMP_HEADER: &str = "X-Neko-Timestamp";
+const NONCE_HEADER: &str = "X-Neko-Nonce";
+const SIGNATURE_HEADER: &str = "X-Neko-Signature";
+const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+lazy_static::lazy_static! {
+    static ref LOCAL_KEYS: LocalKeys = LocalKeys::new(MAX_LOCAL_KEYS);
+}
+
+/// Nonces and idempotency keys of this instance, used while Redis is unavailable.
+/// Other instances do not see them, so during an outage a request can be replayed
+/// once on every instance.
+struct LocalKeys {
+    keys: Mutex<HashMap<String, (String, Instant)>>,
+    max: usize,
+}
+
+enum LocalClaim {
+    Claimed,
+    /// The key exists, with its value
+    Exists(String),
+    /// Too many keys are stored
+    Full,
+}
+
+impl LocalKeys {
+    fn new(max: usize) -> Self {
+        LocalKeys {
+            keys: Mutex::new(HashMap::new()),
+            max,
+        }
+    }
+
+    /// Set a key that expires after `ttl` seconds, unless it exists
+    fn claim(&self, key: &str, value: &str, ttl: u64) -> LocalClaim {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        if let Some((stored, _)) = keys.get(key).filter(|(_, expires)| *expires > now) {
+            return LocalClaim::Exists(stored.clone());
+        }
+        if keys.len() >= self.max {
+            keys.retain(|_, (_, expires)| *expires > now);
+            if keys.len() >= self.max {
+                return LocalClaim::Full;
+            }
+        }
+        let expires = now + Duration::from_secs(ttl);
+        keys.insert(key.to_string(), (value.to_string(), expires));
+        LocalClaim::Claimed
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let keys = self.keys.lock().unwrap();
+        keys.get(key)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(value, _)| value.clone())
+    }
+
+    /// Replace the value of a key that was claimed before
+    fn set(&self, key: &str, value: String, ttl: u64) {
+        let expires = Instant::now() + Duration::from_secs(ttl);
+        let mut keys = self.keys.lock().unwrap();
+        keys.insert(key.to_string(), (value, expires));
+    }
+
+    fn remove(&self, key: &str) {
+        self.keys.lock().unwrap().remove(key);
+    }
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    results: BTreeMap<String, &'static str>,
+}
+
+/// Response of an add request, stored in Redis to answer retries
+#[derive(Serialize, Deserialize)]
+struct Outcome {
+    status: u16,
+    body: String,
+    json: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+/// Stored under an idempotency key, with the hash of the request payload and the
+/// response once the request finished
+#[derive(Serialize, Deserialize)]
+struct Claim {
+    fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outcome: Option<Outcome>,
+}
+
+impl Outcome {
+    fn text(status: StatusCode, body: &str) -> Self {
+        Outcome {
+            status: status.as_u16(),
+            body: body.to_string(),
+            json: false,
+            retry_after: None,
+        }
+    }
+
+    fn json<T: Serialize>(status: StatusCode, value: &T) -> Self {
+        Outcome {
+            status: status.as_u16(),
+            body: serde_json::to_string(value).unwrap(),
+            json: true,
+            retry_after: None,
+        }
+    }
+
+    fn error(status: StatusCode, message: &str) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    fn throttled(mut self, retry_after: u64) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Throttled, unauthorized and failed requests can be retried with the same key
+    fn is_final(&self) -> bool {
+        !matches!(self.status, 304 | 401 | 429 | 500..)
+    }
+
+    fn into_response(self, replayed: bool) -> warp::reply::Response {
+        let mut response = Response::builder().status(self.status);
+        if self.json {
+            response = response
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-store");
+        } else {
+            response = response.header("Content-Type", "text/plain; charset=utf-8");
+        }
+        if let Some(retry_after) = self.retry_after {
+            response = response.header("Retry-After", retry_after);
+        }
+        if replayed {
+            response = response.header("Idempotent-Replayed", "true");
+        }
+        response.body(self.body).unwrap().into_response()
+    }
+}
+
+pub async fn add(
+    name: String,
+    count: String,
+    headers: HeaderMap,
+    redis: ConnectionManager,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let fingerprint = fingerprint(format!("{}/{}", name, count).as_bytes());
+    let request = add_single(name, count, headers.clone(), redis.clone());
+    Ok(idempotent(&headers, redis, fingerprint, request).await)
+}
+
+/// Add images to multiple sources at once. The body is a JSON object of source names and counts.
+pub async fn add_batch(
+    headers: HeaderMap,
+    body: Bytes,
+    redis: ConnectionManager,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let fingerprint = fingerprint(&body);
+    let request = add_many(headers.clone(), body, redis.clone());
+    Ok(idempotent(&headers, redis, fingerprint, request).await)
+}
+
+/// Answer batch requests whose body was not read, instead of passing them on to the other routes
+pub async fn reject_batch(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let outcome = if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        Outcome::error(StatusCode::PAYLOAD_TOO_LARGE, "body too large")
+    } else if rejection.find::<warp::reject::LengthRequired>().is_some() {
+        Outcome::error(StatusCode::LENGTH_REQUIRED, "content length required")
+    } else {
+        return Err(rejection);
+    };
+    Ok(outcome.into_response(false))
+}
+
+async fn add_single(
+    name: String,
+    count: String,
+    headers: HeaderMap,
+    mut redis: ConnectionManager,
+) -> Outcome {
+    let headers = &headers;
+    let Some(source) = sources::resolve(&name) else {
+        return Outcome::text(StatusCode::NOT_ACCEPTABLE, "Unknown Source");
+    };
+    let Ok(images) = count.parse::<u8>() else {
+        return Outcome::text(StatusCode::NOT_ACCEPTABLE, "Number to large");
+    };
+    let images = images as u64;
+
+    // Limit how often a single client can add images
+    match rate_limit::check(&mut redis, headers, images).await {
+        Limit::Allowed => {}
+        Limit::Throttled(retry_after) => {
+            return Outcome::text(StatusCode::TOO_MANY_REQUESTS, "Too many requests")
+                .throttled(retry_after)
+        }
+        Limit::TooLarge => return Outcome::text(StatusCode::PAYLOAD_TOO_LARGE, "Too many images"),
+    }
+
+    // Check for a valid signature or the legacy header, signed over the path as it was sent
+    if let Err((message, status)) =
+        authorize(headers, &format!("{}/{}", name, count), &mut redis).await
+    {
+        return Outcome::text(status, message);
+    }
+
+    // Aggregate the increment with others, the flush task writes them to Redis
+    if buffer::is_write_behind() {
+        return match buffer::push(&[(source, images)]) {
+            true => Outcome::text(StatusCode::OK, "OK"),
+            false => Outcome::text(StatusCode::NOT_MODIFIED, ""),
+        };
+    }
+
+    // Increment the count
+    let r = sources::increment(&mut redis, [(source.as_str(), images)]).await;
+
+    match r {
+        Ok(_) => Outcome::text(StatusCode::OK, "OK"),
+        // Keep the increment until Redis is available again
+        Err(_) if buffer::push(&[(source, images)]) => {
+            Outcome::text(StatusCode::ACCEPTED, "Buffered")
+        }
+        Err(_) => Outcome::text(StatusCode::NOT_MODIFIED, ""),
+    }
+}
+
+async fn add_many(headers: HeaderMap, body: Bytes, mut redis: ConnectionManager) -> Outcome {
+    let headers = &headers;
+    let Ok(payload) = std::str::from_utf8(&body) else {
+        return Outcome::error(StatusCode::BAD_REQUEST, "invalid body");
+    };
+    let Ok(request) = serde_json::from_str::<BTreeMap<String, serde_json::Value>>(payload) else {
+        return Outcome::error(StatusCode::BAD_REQUEST, "invalid body");
+    };
+    if request.is_empty() || request.len() > MAX_BATCH_SOURCES {
+        return Outcome::error(StatusCode::BAD_REQUEST, "invalid source count");
+    }
+
+    // Validate every entry, only valid entries are applied
+    let mut results = BTreeMap::new();
+    let mut increments = Vec::new();
+    for (name, count) in request {
+        let count = count.as_u64().filter(|count| *count <= MAX_BATCH_COUNT);
+        let result = match (sources::resolve(&name), count) {
+            (None, _) => "unknown source",
+            (_, None) => "invalid count",
+            (Some(source), Some(count)) => {
+                increments.push((source, count));
+                "ok"
+            }
+        };
+        results.insert(name, result);
+    }
+    if increments.is_empty() {
+        return Outcome::json(StatusCode::NOT_ACCEPTABLE, &BatchResponse { results });
+    }
+    let images: u64 = increments.iter().map(|(_, count)| count).sum();
+
+    // Limit how often a single client can add images
+    match rate_limit::check(&mut redis, headers, images).await {
+        Limit::Allowed => {}
+        Limit::Throttled(retry_after) => {
+            return Outcome::error(StatusCode::TOO_MANY_REQUESTS, "too many requests")
+                .throttled(retry_after)
+        }
+        Limit::TooLarge => return Outcome::error(StatusCode::PAYLOAD_TOO_LARGE, "too many images"),
+    }
+
+    // Check for a valid signature or the legacy header
+    if let Err((message, status)) = authorize(headers, payload, &mut redis).await {
+        return Outcome::error(status, message);
+    }
+
+    if buffer::is_write_behind() {
+        return match buffer::push(&increments) {
+            true => Outcome::json(StatusCode::OK, &BatchResponse { results }),
+            false => Outcome::error(StatusCode::SERVICE_UNAVAILABLE, "buffer full"),
+        };
+    }
+
+    // Apply all increments at once
+    let applied = increments
+        .iter()
+        .map(|(source, count)| (source.as_str(), *count));
+    if let Err(error) = sources::increment(&mut redis, applied).await {
+        log::error!("Failed to add batch to Redis: {}", error);
+        if buffer::push(&increments) {
+            return Outcome::json(StatusCode::ACCEPTED, &BatchResponse { results });
+        }
+        return Outcome::error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable");
+    }
+
+    Outcome::json(StatusCode::OK, &BatchResponse { results })
+}
+
+/// Run an add request at most once per `Idempotency-Key`.
+///
+/// The key is claimed in Redis before the request runs and replaced with the response
+/// afterwards, so retries get the original response instead of adding the images again.
+/// The request runs in its own task, so it finishes and stores its response even if the
+/// client disconnects. Responses that did not change the counters release the key so the
+/// client can retry. Keys are bound to the `fingerprint` of the request payload.
+/// While Redis is unavailable keys are kept in memory. Requests without the header are always run.
+async fn idempotent(
+    headers: &HeaderMap,
+    mut redis: ConnectionManager,
+    fingerprint: String,
+    request: impl Future<Output = Outcome> + Send + 'static,
+) -> warp::reply::Response {
+    let Some(key) = headers.get(IDEMPOTENCY_HEADER) else {
+        return request.await.into_response(false);
+    };
+    let Some(key) = key
+        .to_str()
+        .ok()
+        .filter(|key| is_valid_idempotency_key(key))
+    else {
+        return Outcome::text(StatusCode::BAD_REQUEST, "Invalid Idempotency-Key")
+            .into_response(false);
+    };
+    let key = format!("add:idempotency:{}", key);
+    let window = idempotency_window();
+
+    // Keys that were used during an outage of Redis are only known in memory
+    let pending = Claim {
+        fingerprint,
+        outcome: None,
+    };
+    if let Some(stored) = LOCAL_KEYS.get(&key) {
+        let (outcome, replayed) = answer_retry(Some(&stored), &pending.fingerprint);
+        return outcome.into_response(replayed);
+    }
+
+    // The claim expires soon, so a crash does not block retries for the whole window
+    let value = serde_json::to_string(&pending).unwrap();
+    let claimed: Result<Option<String>, _> = redis::cmd("SET")
+        .arg(&key)
+        .arg(&value)
+        .arg("NX")
+        .arg("EX")
+        .arg(PENDING_SECONDS)
+        .query_async(&mut redis)
+        .await;
+    let local = match claimed {
+        Ok(Some(_)) => false,
+        Ok(None) => {
+            let stored: Option<String> = redis.get(&key).await.unwrap_or_default();
+            let (outcome, replayed) = answer_retry(stored.as_deref(), &pending.fingerprint);
+            return outcome.into_response(replayed);
+        }
+        Err(error) => {
+            log::warn!("Keeping idempotency key in memory, Redis failed: {}", error);
+            match LOCAL_KEYS.claim(&key, &value, PENDING_SECONDS) {
+                LocalClaim::Claimed => true,
+                LocalClaim::Exists(stored) => {
+                    let (outcome, replayed) = answer_retry(Some(&stored), &pending.fingerprint);
+                    return outcome.into_response(replayed);
+                }
+                LocalClaim::Full => {
+                    return Outcome::text(StatusCode::SERVICE_UNAVAILABLE, "").into_response(false)
+                }
+            }
+        }
+    };
+
+    let task = tokio::spawn(async move {
+        let outcome = request.await;
+        if !outcome.is_final() {
+            if local {
+                LOCAL_KEYS.remove(&key);
+            } else if let Err(error) = redis.del::<_, ()>(&key).await {
+                log::error!("Failed to release idempotency key: {}", error);
+            }
+            return outcome;
+        }
+
+        let claim = Claim {
+            fingerprint: pending.fingerprint,
+            outcome: Some(outcome),
+        };
+        let value = serde_json::to_string(&claim).unwrap();
+        if !local {
+            match redis.set_ex::<_, _, ()>(&key, &value, window).await {
+                Ok(()) => return claim.outcome.unwrap(),
+                Err(error) => log::error!("Failed to save idempotency key: {}", error),
+            }
+        }
+        LOCAL_KEYS.set(&key, value, window);
+        claim.outcome.unwrap()
+    });
+    match task.await {
+        Ok(outcome) => outcome.into_response(false),
+        Err(_) => Outcome::text(StatusCode::INTERNAL_SERVER_ERROR, "").into_response(false),
+    }
+}
+
+/// Answer a retry from the claim stored under its idempotency key. Returns the response
+/// and if it replays the response of the first request.
+fn answer_retry(stored: Option<&str>, fingerprint: &str) -> (Outcome, bool) {
+    let claim = stored.and_then(|stored| serde_json::from_str::<Claim>(stored).ok());
+    match claim {
+        Some(claim) if claim.fingerprint != fingerprint => (
+            Outcome::text(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency-Key was used for another request",
+            ),
+            false,
+        ),
+        Some(Claim {
+            outcome: Some(outcome),
+            ..
+        }) => (outcome, true),
+        // The first request is still running
+        _ => (
+            Outcome::text(StatusCode::CONFLICT, "Request is still running"),
+            false,
+        ),
+    }
+}
+
+/// Hex encoded SHA-256 of a request payload
+fn fingerprint(payload: &[u8]) -> String {
+    Sha256::digest(payload)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn idempotency_window() -> u64 {
+    env::var("ADD_IDEMPOTENCY_WINDOW_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|window| *window > 0)
+        .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_SECONDS)
+}
+
+fn is_valid_idempotency_key(key: &str) -> bool {
+    (1..=MAX_IDEMPOTENCY_KEY_LENGTH).contains(&key.len())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Check that a request was sent by the plugin.
+///
+/// Signed requests carry a timestamp, a nonce and the hex encoded HMAC-SHA256 of
+/// `<payload>/<timestamp>/<nonce>` with the secret from `ADD_SIGNING_SECRET`.
+/// The payload is `<source>/<count>` for single requests, exactly as both segments appear in
+/// the path, and the raw body for batches.
+/// Unsigned requests are only accepted if `ADD_LEGACY_AGENT_CHECK` is enabled and the
+/// `User-Agent` contains `NekoFans`.
+async fn authorize(
+    headers: &HeaderMap,
+    payload: &str,
+    redis: &mut ConnectionManager,
+) -> Result<(), (&'static str, StatusCode)> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let unauthorized = ("Valid request, but unauthorized", StatusCode::UNAUTHORIZED);
+
+    let (timestamp, nonce, signature) = match (
+        header(TIMESTAMP_HEADER),
+        header(NONCE_HEADER),
+        header(SIGNATURE_HEADER),
+    ) {
+        (Some(timestamp), Some(nonce), Some(signature)) => (timestamp, nonce, signature),
+        (None, None, None) => {
+            return match header("User-Agent") {
+                Some(agent) if legacy_agent_check() && agent.contains("NekoFans") => Ok(()),
+                _ => Err(unauthorized),
+            };
+        }
+        _ => return Err(unauthorized),
+    };
+
+    let secret = match env::var("ADD_SIGNING_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret,
+        _ => return Err(unauthorized),
+    };
+    let Ok(time) = timestamp.parse::<i64>() else {
+        return Err(unauthorized);
+    };
+    if (chrono::Utc::now().timestamp() - time).abs() > MAX_CLOCK_SKEW_SECONDS
+        || !is_valid_nonce(nonce)
+    {
+        return Err(unauthorized);
+    }
+    let message = format!("{}/{}/{}", payload, timestamp, nonce);
+    if !verify_signature(secret.as_bytes(), message.as_bytes(), signature) {
+        return Err(unauthorized);
+    }
+
+    // Remember the nonce until the timestamp is too old anyway
+    let key = format!("add:nonce:{}", nonce);
+    let fresh: redis::RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(&key)
+        .arg("1")
+        .arg("NX")
+        .arg("EX")
+        .arg(2 * MAX_CLOCK_SKEW_SECONDS)
+        .query_async(redis)
+        .await;
+    check_nonce(fresh, &key, &LOCAL_KEYS)
+}
+
+/// Accept a nonce that was stored for the first time, in Redis or in `local` if Redis failed
+fn check_nonce(
+    stored: redis::RedisResult<Option<String>>,
+    key: &str,
+    local: &LocalKeys,
+) -> Result<(), (&'static str, StatusCode)> {
+    let unauthorized = ("Valid request, but unauthorized", StatusCode::UNAUTHORIZED);
+    let fresh = match stored {
+        Ok(stored) => stored.is_some() && local.get(key).is_none(),
+        Err(error) => {
+            log::warn!("Keeping nonce in memory, Redis failed: {}", error);
+            match local.claim(key, "1", 2 * MAX_CLOCK_SKEW_SECONDS as u64) {
+                LocalClaim::Claimed => true,
+                LocalClaim::Exists(_) => false,
+                LocalClaim::Full => return Err(("", StatusCode::SERVICE_UNAVAILABLE)),
+            }
+        }
+    };
+    if !fresh {
+        log::warn!("Rejected replayed add request with {}", key);
+        return Err(unauthorized);
+    }
+    Ok(())
+}
+
+fn legacy_agent_check() -> bool {
+    env::var("ADD_LEGACY_AGENT_CHECK")
+        .map(|value| value != "false" && value != "0")
+        .unwrap_or(true)
+}
+
+fn is_valid_nonce(nonce: &str) -> bool {
+    (MIN_NONCE_LENGTH..=MAX_NONCE_LENGTH).contains(&nonce.len())
+        && nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// Compare the hex encoded signature with the HMAC of the message in constant time
+fn verify_signature(secret: &[u8], message: &[u8], signature: &str) -> bool {
+    let Some(signature) = decode_hex(signature) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(message);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &[u8], message: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(message);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let message = b"nekos.best/12/1767225600/abcdef0123";
+        let signature = sign(b"secret", message);
+
+        assert!(verify_signature(b"secret", message, &signature));
+        assert!(verify_signature(
+            b"secret",
+            message,
+            &signature.to_uppercase()
+        ));
+        assert!(!verify_signature(b"other", message, &signature));
+        assert!(!verify_signature(
+            b"secret",
+            b"nekos.best/255/1767225600/abcdef0123",
+            &signature
+        ));
+        assert!(!verify_signature(b"secret", message, "not hex"));
+    }
+
+    #[test]
+    fn validates_nonces() {
+        assert!(is_valid_nonce("0123456789abcdef"));
+        assert!(!is_valid_nonce("short"));
+        assert!(!is_valid_nonce("with spaces in it"));
+        assert!(!is_valid_nonce(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn releases_keys_of_retryable_responses() {
+        assert!(Outcome::text(StatusCode::OK, "OK").is_final());
+        assert!(Outcome::text(StatusCode::NOT_ACCEPTABLE, "Unknown Source").is_final());
+        assert!(!Outcome::text(StatusCode::NOT_MODIFIED, "").is_final());
+        assert!(!Outcome::error(StatusCode::TOO_MANY_REQUESTS, "too many requests").is_final());
+        assert!(
+            !Outcome::error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable").is_final()
+        );
+        assert!(is_valid_idempotency_key(
+            "6f1c2b1e-8a4d-4a53-9f0e-0c4b5c1d2e3f"
+        ));
+        assert!(!is_valid_idempotency_key(""));
+        assert!(!is_valid_idempotency_key("with spaces"));
+        assert!(!is_valid_idempotency_key(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn binds_keys_to_payloads() {
+        let fingerprint = fingerprint(b"nekos.best/12");
+        let claim = |outcome| {
+            serde_json::to_string(&Claim {
+                fingerprint: fingerprint.clone(),
+                outcome,
+            })
+            .unwrap()
+        };
+
+        let (outcome, replayed) = answer_retry(Some(&claim(None)), &fingerprint);
+        assert_eq!((outcome.status, replayed), (409, false));
+        let stored = claim(Some(Outcome::text(StatusCode::OK, "OK")));
+        let (outcome, replayed) = answer_retry(Some(&stored), &fingerprint);
+        assert_eq!(
+            (outcome.status, outcome.body.as_str(), replayed),
+            (200, "OK", true)
+        );
+        let (outcome, replayed) =
+            answer_retry(Some(&stored), &super::fingerprint(b"nekos.best/255"));
+        assert_eq!((outcome.status, replayed), (422, false));
+        // The key expired between the claim and the lookup
+        assert_eq!(answer_retry(None, &fingerprint).0.status, 409);
+    }
+
+    #[test]
+    fn keeps_nonces_in_memory_without_redis() {
+        let down = || Err(redis::RedisError::from((redis::ErrorKind::Io, "refused")));
+        let local = LocalKeys::new(2);
+        let status = |result: Result<(), (&str, StatusCode)>| result.err().map(|error| error.1);
+
+        assert_eq!(status(check_nonce(down(), "add:nonce:a", &local)), None);
+        assert_eq!(
+            status(check_nonce(down(), "add:nonce:a", &local)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        // Nonces used during the outage stay rejected once Redis is back
+        assert_eq!(
+            status(check_nonce(Ok(Some("OK".into())), "add:nonce:a", &local)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(status(check_nonce(down(), "add:nonce:b", &local)), None);
+        assert_eq!(
+            status(check_nonce(down(), "add:nonce:c", &local)),
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(
+            status(check_nonce(Ok(Some("OK".into())), "add:nonce:c", &local)),
+            None
+        );
+    }
+}
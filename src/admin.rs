@@ -0,0 +1,228 @@
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::env;
+use warp::http::Response;
+use warp::hyper::StatusCode;
+
+use crate::json::{json_error, json_response};
+use crate::rate_limit;
+use crate::sources::{self, RegistryError, Source, SourceChange};
+
+const MAX_THROTTLED_CLIENTS: isize = 100;
+
+#[derive(Deserialize)]
+pub struct NewSource {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct SourceUpdate {
+    name: Option<String>,
+    #[serde(flatten)]
+    change: SourceChange,
+}
+
+#[derive(Deserialize)]
+pub struct SourceMerge {
+    into: String,
+}
+
+#[derive(Serialize)]
+struct MergeResponse {
+    source: Source,
+    merged: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ThrottledQuery {
+    date: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThrottledResponse {
+    date: String,
+    clients: Vec<ThrottledClient>,
+}
+
+#[derive(Serialize)]
+struct ThrottledClient {
+    client: String,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+struct SourcesResponse {
+    sources: Vec<Source>,
+}
+
+/// Returns every registered source
+pub async fn list_sources(
+    authorization: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err((status, message)) = authorize(authorization) {
+        return Ok(json_error(status, message));
+    }
+
+    Ok(json_response(
+        StatusCode::OK,
+        &SourcesResponse {
+            sources: sources::all(),
+        },
+        None,
+    ))
+}
+
+/// Register a new source
+pub async fn add_source(
+    authorization: Option<String>,
+    request: NewSource,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err((status, message)) = authorize(authorization) {
+        return Ok(json_error(status, message));
+    }
+
+    Ok(match sources::add(&mut redis, &request.name).await {
+        Ok(source) => {
+            log::info!("Registered image source {}", source.name);
+            json_response(StatusCode::CREATED, &source, None)
+        }
+        Err(error) => registry_error(error),
+    })
+}
+
+/// Enable, disable or rename a source
+pub async fn update_source(
+    name: String,
+    authorization: Option<String>,
+    request: SourceUpdate,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err((status, message)) = authorize(authorization) {
+        return Ok(json_error(status, message));
+    }
+    let change = request.change;
+    if request.name.is_none()
+        && change.enabled.is_none()
+        && change.deprecated.is_none()
+        && change.aliases.is_none()
+    {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "nothing to update"));
+    }
+
+    let mut name = name;
+    if let Some(new_name) = request.name {
+        match sources::rename(&mut redis, &name, &new_name).await {
+            Ok(source) => {
+                log::info!("Renamed image source {} to {}", name, source.name);
+                name = source.name;
+            }
+            Err(error) => return Ok(registry_error(error)),
+        }
+    }
+
+    Ok(match sources::update(&mut redis, &name, change).await {
+        Ok(source) => {
+            log::info!(
+                "Updated image source {}: enabled: {}, deprecated: {}, aliases: {:?}",
+                source.name,
+                source.enabled,
+                source.deprecated,
+                source.aliases
+            );
+            json_response(StatusCode::OK, &source, None)
+        }
+        Err(error) => registry_error(error),
+    })
+}
+
+/// Fold the counter of a source into another source
+pub async fn merge_source(
+    name: String,
+    authorization: Option<String>,
+    request: SourceMerge,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err((status, message)) = authorize(authorization) {
+        return Ok(json_error(status, message));
+    }
+
+    Ok(
+        match sources::merge(&mut redis, &name, &request.into).await {
+            Ok((source, merged)) => {
+                log::info!(
+                    "Merged {} images of source {} into {}",
+                    merged,
+                    name,
+                    source.name
+                );
+                json_response(StatusCode::OK, &MergeResponse { source, merged }, None)
+            }
+            Err(error) => registry_error(error),
+        },
+    )
+}
+
+/// Returns the clients with the most throttled add requests on a day
+pub async fn throttled(
+    authorization: Option<String>,
+    request: ThrottledQuery,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err((status, message)) = authorize(authorization) {
+        return Ok(json_error(status, message));
+    }
+    let date = match request.date {
+        Some(date) => match chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid date")),
+        },
+        None => chrono::Utc::now().date_naive(),
+    };
+
+    Ok(
+        match rate_limit::throttled(&mut redis, date, MAX_THROTTLED_CLIENTS).await {
+            Ok(clients) => json_response(
+                StatusCode::OK,
+                &ThrottledResponse {
+                    date: date.format("%Y-%m-%d").to_string(),
+                    clients: clients
+                        .into_iter()
+                        .map(|(client, requests)| ThrottledClient { client, requests })
+                        .collect(),
+                },
+                None,
+            ),
+            Err(_) => json_error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable"),
+        },
+    )
+}
+
+/// Check the bearer token against `ADMIN_TOKEN`. The admin API is disabled if it is not set.
+fn authorize(authorization: Option<String>) -> Result<(), (StatusCode, &'static str)> {
+    let token = match env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return Err((StatusCode::FORBIDDEN, "admin api disabled")),
+    };
+
+    match authorization
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+    {
+        Some(given) if given == token => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "unauthorized")),
+    }
+}
+
+fn registry_error(error: RegistryError) -> Response<String> {
+    match error {
+        RegistryError::InvalidName => json_error(StatusCode::BAD_REQUEST, "invalid source name"),
+        RegistryError::NotFound => json_error(StatusCode::NOT_FOUND, "unknown source"),
+        RegistryError::AlreadyExists => json_error(StatusCode::CONFLICT, "source already exists"),
+        RegistryError::AliasInUse => json_error(StatusCode::CONFLICT, "name already in use"),
+        RegistryError::Redis(error) => {
+            log::error!("Failed to update image sources in Redis: {}", error);
+            json_error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        }
+    }
+}
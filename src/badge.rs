@@ -0,0 +1,163 @@
+use serde::Serialize;
+use warp::http::Response;
+use warp::hyper::StatusCode;
+
+use crate::{sources, IMAGE_CACHE};
+
+const TOTAL_LABEL: &str = "neko fans";
+const COLOR: &str = "ec6192";
+const UNAVAILABLE_COLOR: &str = "9f9f9f";
+/// Space between the text and the border of each half of the badge
+const PADDING: u32 = 10;
+
+/// Width of the printable ASCII characters in Verdana 11px, starting with the space
+const VERDANA_WIDTHS: [f32; 95] = [
+    3.87, 4.33, 5.05, 9.0, 6.99, 11.84, 7.99, 2.95, 4.99, 4.99, 6.99, 9.0, 4.0, 4.99, 4.0, 4.99,
+    6.99, 6.99, 6.99, 6.99, 6.99, 6.99, 6.99, 6.99, 6.99, 6.99, 4.99, 4.99, 9.0, 9.0, 9.0, 6.0,
+    11.0, 7.52, 7.54, 7.68, 8.48, 6.96, 6.32, 8.53, 8.27, 4.63, 5.0, 7.62, 6.12, 9.27, 8.23, 8.66,
+    6.63, 8.66, 7.65, 7.52, 6.78, 8.05, 7.52, 10.88, 7.54, 6.77, 7.54, 4.99, 4.99, 4.99, 9.0, 6.99,
+    6.99, 6.61, 6.82, 5.73, 6.82, 6.55, 3.87, 6.82, 6.96, 3.02, 3.78, 6.51, 3.02, 10.7, 6.96, 6.67,
+    6.82, 6.82, 4.69, 5.73, 4.33, 6.96, 6.51, 8.98, 6.51, 6.51, 5.78, 6.98, 4.99, 6.98, 9.0,
+];
+/// Width of characters that are not in the table, the width of `m`
+const FALLBACK_WIDTH: f32 = 10.7;
+
+/// Response in the shields.io endpoint schema
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    schema_version: u8,
+    label: String,
+    message: String,
+    color: &'static str,
+}
+
+/// Returns the badge of the total or a source, as `<name>.svg` or `<name>.json`
+pub async fn badge(file: String) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some((name, extension)) = file.rsplit_once('.') else {
+        return Err(warp::reject::not_found());
+    };
+    let label = match name {
+        sources::TOTAL_KEY => TOTAL_LABEL,
+        name if sources::is_source(name) => name,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("Unknown Source".to_string())
+                .unwrap())
+        }
+    };
+
+    // Counters are updated by the update task
+    let (message, color) = match IMAGE_CACHE.get_counter(name).await {
+        Some(count) => (metric(count), COLOR),
+        None => ("unavailable".to_string(), UNAVAILABLE_COLOR),
+    };
+
+    let (content_type, body) = match extension {
+        "svg" => ("image/svg+xml", create_badge(label, &message, color)),
+        "json" => (
+            "application/json",
+            serde_json::to_string(&Endpoint {
+                schema_version: 1,
+                label: label.to_string(),
+                message,
+                color,
+            })
+            .unwrap(),
+        ),
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+/// Render a badge in the flat style of shields.io
+fn create_badge(label: &str, message: &str, color: &str) -> String {
+    let label_text = text_width(label);
+    let message_text = text_width(message);
+    let label_width = label_text + PADDING;
+    let message_width = message_text + PADDING;
+    let width = label_width + message_width;
+    let (label, message) = (escape(label), escape(message));
+
+    // Text is drawn at ten times the size and scaled down, like shields.io does for sharper text
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="#{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" text-rendering="geometricPrecision" font-size="110"><text aria-hidden="true" x="{label_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{label_length}">{label}</text><text x="{label_x}" y="140" transform="scale(.1)" fill="#fff" textLength="{label_length}">{label}</text><text aria-hidden="true" x="{message_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{message_length}">{message}</text><text x="{message_x}" y="140" transform="scale(.1)" fill="#fff" textLength="{message_length}">{message}</text></g></svg>"##,
+        label_x = label_width * 5,
+        label_length = label_text * 10,
+        message_x = label_width * 10 + message_width * 5,
+        message_length = message_text * 10,
+    )
+}
+
+/// Width of a text in Verdana 11px, rounded to whole pixels
+fn text_width(text: &str) -> u32 {
+    let width: f32 = text
+        .chars()
+        .map(|c| match c {
+            ' '..='~' => VERDANA_WIDTHS[c as usize - ' ' as usize],
+            _ => FALLBACK_WIDTH,
+        })
+        .sum();
+    width.round() as u32
+}
+
+/// Format a number with a metric prefix like `1.2k` or `34M`
+fn metric(count: u64) -> String {
+    const PREFIXES: [&str; 6] = ["k", "M", "G", "T", "P", "E"];
+    let mut value = count as f64;
+    let mut prefix = "";
+    for next in PREFIXES {
+        if value.round() < 1000.0 {
+            break;
+        }
+        value /= 1000.0;
+        prefix = next;
+    }
+
+    if prefix.is_empty() {
+        count.to_string()
+    } else if value < 9.95 {
+        let value = format!("{:.1}", value);
+        format!("{}{}", value.strip_suffix(".0").unwrap_or(&value), prefix)
+    } else {
+        format!("{:.0}{}", value, prefix)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_metric_numbers() {
+        assert_eq!(metric(0), "0");
+        assert_eq!(metric(999), "999");
+        assert_eq!(metric(1000), "1k");
+        assert_eq!(metric(1234), "1.2k");
+        assert_eq!(metric(12_345), "12k");
+        assert_eq!(metric(999_600), "1M");
+        assert_eq!(metric(1_500_000), "1.5M");
+        assert_eq!(metric(u64::MAX), "18E");
+    }
+
+    #[test]
+    fn measures_badge_text() {
+        assert_eq!(text_width("1.2M"), 27);
+        assert_eq!(text_width("nekos.best"), 60);
+        assert!(create_badge("neko fans", "1.2M", COLOR)
+            .starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="101" height="20""#));
+    }
+}
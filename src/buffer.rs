@@ -0,0 +1,281 @@
+use redis::aio::ConnectionManager;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::sources;
+
+/// How often buffered increments are retried when they are not written behind
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_WRITE_BEHIND_MS: u64 = 500;
+/// Maximum number of increments that are buffered while Redis is unavailable
+const DEFAULT_MAX_PENDING: u64 = 100_000;
+
+#[derive(Default)]
+struct Pending {
+    counts: BTreeMap<String, u64>,
+    /// Number of increments that were added to `counts`
+    increments: u64,
+}
+
+lazy_static::lazy_static! {
+    // Increments that were not written to Redis yet
+    static ref PENDING: Mutex<Pending> = Mutex::new(Pending::default());
+
+    // Writer of the journal, if it is enabled
+    static ref JOURNAL: Mutex<Option<Journal>> = Mutex::new(journal_path().map(Journal::start));
+}
+
+/// Change of the journal file
+enum JournalWrite {
+    /// Add entries to the end
+    Append(String),
+    /// Replace every entry
+    Rewrite(String),
+}
+
+/// Writes the journal on its own thread, so requests do not wait for the disk.
+/// Writes are sent while `PENDING` is locked, so they are applied in the order the buffer changed.
+struct Journal {
+    writes: mpsc::Sender<JournalWrite>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Journal {
+    fn start(path: String) -> Self {
+        let (writes, received) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for write in received {
+                let written = match write {
+                    JournalWrite::Append(entries) => OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .and_then(|mut file| file.write_all(entries.as_bytes())),
+                    JournalWrite::Rewrite(entries) => {
+                        let temporary = format!("{}.tmp", path);
+                        fs::write(&temporary, entries).and_then(|_| fs::rename(&temporary, &path))
+                    }
+                };
+                if let Err(error) = written {
+                    log::error!("Failed to write buffer journal {}: {}", path, error);
+                }
+            }
+        });
+        Journal { writes, thread }
+    }
+}
+
+/// Increments taken from the buffer to be written to Redis.
+/// They are put back into the buffer when dropped, unless they were written.
+struct Taken(Option<Pending>);
+
+impl Taken {
+    /// Mark the increments as written. Returns their number.
+    fn written(mut self) -> u64 {
+        self.0.take().map_or(0, |taken| taken.increments)
+    }
+}
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        let Some(taken) = self.0.take() else {
+            return;
+        };
+        let mut pending = PENDING.lock().unwrap();
+        for (source, count) in taken.counts {
+            *pending.counts.entry(source).or_default() += count;
+        }
+        pending.increments += taken.increments;
+    }
+}
+
+/// Number of increments that were lost because the buffer was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Buffer increments until the flush task writes them to Redis.
+/// If the buffer is full none of them are buffered and they are counted as dropped.
+pub fn push(increments: &[(String, u64)]) -> bool {
+    let mut pending = PENDING.lock().unwrap();
+    let added = increments.len() as u64;
+    if pending.increments + added > max_pending() {
+        DROPPED.fetch_add(added, Ordering::Relaxed);
+        log::error!("Dropped {} increments, the buffer is full", added);
+        return false;
+    }
+
+    for (source, count) in increments {
+        *pending.counts.entry(source.clone()).or_default() += count;
+    }
+    pending.increments += added;
+
+    // Append to the journal so the increments survive a restart
+    let entries = increments.iter().map(|(source, count)| (source, count));
+    write_journal(JournalWrite::Append(journal(entries)));
+    true
+}
+
+/// Write every buffered increment to Redis in one transaction. The increments stay
+/// buffered if it fails or the future is dropped. Returns the number of written increments.
+pub async fn flush(redis: &mut ConnectionManager) -> Result<u64, redis::RedisError> {
+    let taken = Taken(Some(std::mem::take(&mut *PENDING.lock().unwrap())));
+    let Some(counts) = taken.0.as_ref().map(|taken| &taken.counts) else {
+        return Ok(0);
+    };
+    if counts.is_empty() {
+        return Ok(0);
+    }
+
+    let increments = counts
+        .iter()
+        .map(|(source, count)| (source.as_str(), *count));
+    sources::increment(redis, increments).await?;
+
+    // Only keep what was buffered in the meantime in the journal
+    let pending = PENDING.lock().unwrap();
+    write_journal(JournalWrite::Rewrite(journal(&pending.counts)));
+    Ok(taken.written())
+}
+
+/// Wait until every change of the journal is written, and stop the journal writer
+pub fn close_journal() {
+    if let Some(journal) = JOURNAL.lock().unwrap().take() {
+        drop(journal.writes);
+        if journal.thread.join().is_err() {
+            log::error!("Buffer journal writer failed");
+        }
+    }
+}
+
+fn write_journal(write: JournalWrite) {
+    if let Some(journal) = JOURNAL.lock().unwrap().as_ref() {
+        // The writer only stops after the journal was closed on shutdown
+        let _ = journal.writes.send(write);
+    }
+}
+
+/// Buffer the increments of the journal that were not written before the last shutdown.
+/// Returns the number of restored increments.
+pub fn load_journal() -> u64 {
+    let Some(path) = journal_path() else {
+        return 0;
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return 0,
+        Err(error) => {
+            log::error!("Failed to read buffer journal {}: {}", path, error);
+            return 0;
+        }
+    };
+
+    let mut pending = PENDING.lock().unwrap();
+    let mut restored = 0;
+    for line in content.lines() {
+        match parse_entry(line) {
+            Some((source, count)) => {
+                *pending.counts.entry(source.to_string()).or_default() += count;
+                restored += 1;
+            }
+            None => log::warn!("Ignoring invalid line in buffer journal: {}", line),
+        }
+    }
+    pending.increments += restored;
+    restored
+}
+
+/// Returns true if every increment is buffered and written to Redis by the flush task
+pub fn is_write_behind() -> bool {
+    write_behind_ms() > 0
+}
+
+/// Returns how often the flush task writes the buffer to Redis
+pub fn flush_interval() -> Duration {
+    match write_behind_ms() {
+        0 => RETRY_INTERVAL,
+        ms => Duration::from_millis(ms),
+    }
+}
+
+/// Returns the number of buffered increments
+pub fn pending() -> u64 {
+    PENDING.lock().unwrap().increments
+}
+
+/// Returns the number of increments that were lost because the buffer was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+fn journal<'a>(increments: impl IntoIterator<Item = (&'a String, &'a u64)>) -> String {
+    increments
+        .into_iter()
+        .map(|(source, count)| format!("{} {}\n", source, count))
+        .collect()
+}
+
+fn parse_entry(line: &str) -> Option<(&str, u64)> {
+    let (source, count) = line.split_once(' ')?;
+    Some((source, count.parse().ok()?)).filter(|(source, _)| !source.is_empty())
+}
+
+fn journal_path() -> Option<String> {
+    env::var("ADD_BUFFER_JOURNAL")
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+fn write_behind_ms() -> u64 {
+    env::var("ADD_WRITE_BEHIND_MS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_WRITE_BEHIND_MS)
+}
+
+fn max_pending() -> u64 {
+    env::var("ADD_BUFFER_MAX_PENDING")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_MAX_PENDING)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_taken_increments_when_dropped() {
+        assert!(push(&[("nekos.best".to_string(), 12)]));
+        let taken = Taken(Some(std::mem::take(&mut *PENDING.lock().unwrap())));
+        assert_eq!(pending(), 0);
+
+        // Like a flush that was cancelled while it waited for Redis
+        drop(taken);
+        assert_eq!(pending(), 1);
+        assert_eq!(PENDING.lock().unwrap().counts["nekos.best"], 12);
+
+        let taken = Taken(Some(std::mem::take(&mut *PENDING.lock().unwrap())));
+        assert_eq!(taken.written(), 1);
+        assert_eq!(pending(), 0);
+    }
+
+    #[test]
+    fn parses_journal_entries() {
+        let counts = BTreeMap::from([("nekos.best".to_string(), 12), ("pic.re".to_string(), 3)]);
+        let journal = journal(&counts);
+
+        assert_eq!(journal, "nekos.best 12\npic.re 3\n");
+        assert_eq!(
+            journal.lines().filter_map(parse_entry).collect::<Vec<_>>(),
+            vec![("nekos.best", 12), ("pic.re", 3)]
+        );
+        assert_eq!(parse_entry("nekos.best"), None);
+        assert_eq!(parse_entry("nekos.best -1"), None);
+        assert_eq!(parse_entry(" 12"), None);
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{Datelike, Days, NaiveDate};
+use image::{imageops, Rgba, RgbaImage};
+
+use crate::const_image;
+
+const WIDTH: u32 = 1172;
+const HEIGHT: u32 = 400;
+const MARGIN: u32 = 12;
+const LABEL_HEIGHT: u32 = 40;
+const GAP_HEIGHT: u32 = 4;
+/// Widest y-axis label, wider labels are cut off on the left
+const MAX_LABEL_WIDTH: u32 = WIDTH / 3;
+
+const BAR_COLOR: Rgba<u8> = Rgba([236, 97, 146, 255]);
+const GAP_COLOR: Rgba<u8> = Rgba([160, 160, 160, 255]);
+const AXIS_COLOR: Rgba<u8> = Rgba([90, 90, 90, 255]);
+const GRID_COLOR: Rgba<u8> = Rgba([200, 200, 200, 160]);
+
+lazy_static::lazy_static! {
+    // Digits scaled down to the height of an axis label
+    static ref LABEL_NUMBERS: Vec<RgbaImage> = const_image::NUMBERS
+        .iter()
+        .map(|number| {
+            let width = number.width() * LABEL_HEIGHT / number.height();
+            imageops::resize(number, width, LABEL_HEIGHT, imageops::FilterType::Triangle)
+        })
+        .collect();
+}
+
+/// Render a bar chart with one bar per day. Days without data are drawn as a small grey marker.
+pub fn create_history_chart(first_day: NaiveDate, downloads: &[Option<u64>]) -> RgbaImage {
+    let mut img = RgbaImage::new(WIDTH, HEIGHT);
+    let max = downloads
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    // Plot area
+    let max_label = label(max);
+    let left = MARGIN + max_label.width().min(MAX_LABEL_WIDTH) + MARGIN;
+    let right = WIDTH - MARGIN;
+    let top = MARGIN + LABEL_HEIGHT / 2;
+    let bottom = HEIGHT - MARGIN - LABEL_HEIGHT - MARGIN;
+    let plot_height = bottom - top;
+
+    // Y-Axis labels with grid lines
+    for (value, y) in [(max, top), (max / 2, top + plot_height / 2), (0, bottom)] {
+        fill(&mut img, (left, y), (right, y + 1), GRID_COLOR);
+        let label = label(value);
+        let x = left as i64 - MARGIN as i64 - label.width() as i64;
+        imageops::overlay(&mut img, &label, x, (y - LABEL_HEIGHT / 2) as i64);
+    }
+
+    // Axes
+    fill(&mut img, (left - 2, top), (left, bottom + 2), AXIS_COLOR);
+    fill(
+        &mut img,
+        (left - 2, bottom),
+        (right, bottom + 2),
+        AXIS_COLOR,
+    );
+
+    // Bars
+    let count = downloads.len().max(1) as u32;
+    let slot_start = |i: u32| left + i * right.saturating_sub(left) / count;
+    for (i, value) in downloads.iter().enumerate() {
+        let (start, end) = (slot_start(i as u32), slot_start(i as u32 + 1));
+        let bar_width = ((end - start) * 3 / 4).max(1);
+        let x = start + (end - start - bar_width) / 2;
+        match value {
+            Some(value) => {
+                let height = (*value as u128 * plot_height as u128 / max as u128) as u32;
+                fill(
+                    &mut img,
+                    (x, bottom - height),
+                    (x + bar_width, bottom),
+                    BAR_COLOR,
+                );
+            }
+            None => fill(
+                &mut img,
+                (x, bottom - GAP_HEIGHT),
+                (x + bar_width, bottom),
+                GAP_COLOR,
+            ),
+        }
+    }
+
+    // X-Axis labels with the day of the month, counted back from the last day
+    let slot = (right.saturating_sub(left) / count).max(1);
+    let label_width = LABEL_NUMBERS[0].width() * 2;
+    let mut step = if downloads.len() > 14 { 7 } else { 1 };
+    while step * slot < label_width + MARGIN {
+        step += step.min(7);
+    }
+    for i in (0..downloads.len()).rev().step_by(step as usize) {
+        let Some(date) = first_day.checked_add_days(Days::new(i as u64)) else {
+            continue;
+        };
+        let label = label(date.day() as u64);
+        let center = (slot_start(i as u32) + slot_start(i as u32 + 1)) / 2;
+        let Some(x) = center.checked_sub(label.width() / 2) else {
+            continue;
+        };
+        if x < left || x + label.width() > WIDTH {
+            continue;
+        }
+        imageops::overlay(&mut img, &label, x as i64, (bottom + MARGIN) as i64);
+    }
+
+    img
+}
+
+/// Render a number with the small digits
+fn label(value: u64) -> RgbaImage {
+    let digits = value.to_string();
+    let digit_width = LABEL_NUMBERS[0].width();
+    let mut img = RgbaImage::new(digit_width * digits.len() as u32, LABEL_HEIGHT);
+    for (i, digit) in digits.chars().enumerate() {
+        let number = &LABEL_NUMBERS[digit.to_digit(10).unwrap() as usize];
+        imageops::overlay(&mut img, number, (i as u32 * digit_width) as i64, 0);
+    }
+    img
+}
+
+/// Fill the rectangle between two corners, the second corner is exclusive
+fn fill(img: &mut RgbaImage, from: (u32, u32), to: (u32, u32), color: Rgba<u8>) {
+    for y in from.1..to.1.min(img.height()) {
+        for x in from.0..to.0.min(img.width()) {
+            img.put_pixel(x, y, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_wide_labels() {
+        let first_day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let img = create_history_chart(first_day, &[Some(u64::MAX), None, Some(1)]);
+        assert_eq!(img.dimensions(), (WIDTH, HEIGHT));
+
+        // The bars start right of the label of the largest value
+        let bottom = HEIGHT - MARGIN - LABEL_HEIGHT - MARGIN;
+        let first_bar = (0..WIDTH).find(|x| *img.get_pixel(*x, bottom - 1) == BAR_COLOR);
+        assert!(first_bar.unwrap() > label(u64::MAX).width() + MARGIN);
+    }
+}
@@ -1,13 +1,102 @@
+use base64::prelude::*;
+use chrono::NaiveDate;
+use image::codecs::avif::AvifEncoder;
 use image::{ImageBuffer, Rgba};
+use sha2::{Digest, Sha256};
 use std::io::Write;
 
-use crate::const_image;
+use crate::chart;
+use crate::image_format::ImageFormat;
 use crate::season_images;
+use crate::text::{self, TextStyle};
+use crate::theme::{self, Theme};
+
+/// Version of the rendered count images. Bump it whenever the output of the same count changes,
+/// so images that were shared in Redis by an older version are not used.
+pub const RENDER_VERSION: u32 = 4;
+/// Longest label that can be shown next to a count
+pub const MAX_LABEL_LENGTH: usize = 32;
+/// Space between the number and its label
+const LABEL_GAP: u32 = 16;
+/// Black text with a white border, like the text of the header
+const LABEL_STYLE: TextStyle = TextStyle {
+    size: 64.0,
+    color: Rgba([0, 0, 0, 255]),
+    outline: Some((Rgba([255, 255, 255, 255]), 3)),
+};
+
+/// How a count image is drawn, besides its number
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CountStyle {
+    /// Text next to the number
+    pub label: Option<String>,
+    /// Name of the digit theme
+    pub theme: &'static str,
+}
+
+impl Default for CountStyle {
+    fn default() -> Self {
+        CountStyle {
+            label: None,
+            theme: theme::DEFAULT,
+        }
+    }
+}
+
+impl CountStyle {
+    /// Returns an error if the label is too long or contains control characters, or if
+    /// the theme does not exist. Empty labels are ignored. Without a theme the digits
+    /// of the current season are used.
+    pub fn new(label: Option<String>, theme: Option<&str>) -> Result<Self, &'static str> {
+        let label = label.filter(|label| !label.trim().is_empty());
+        if let Some(label) = &label {
+            if label.chars().count() > MAX_LABEL_LENGTH || label.chars().any(char::is_control) {
+                return Err("Invalid label");
+            }
+        }
+        let theme = match theme {
+            Some(name) => theme::get(name).ok_or("Unknown theme")?,
+            None => theme::seasonal(),
+        };
+        Ok(CountStyle {
+            label,
+            theme: theme.name(),
+        })
+    }
+
+    /// Suffix of the render cache key of images in this style, empty for the default style
+    pub fn cache_key(&self) -> String {
+        let mut key = String::new();
+        if self.theme != theme::DEFAULT {
+            key += &format!(":theme-{}", self.theme);
+        }
+        if let Some(label) = &self.label {
+            let digest = Sha256::digest(label.as_bytes());
+            let hex: String = digest[..8]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            key += &format!(":label-{}", hex);
+        }
+        key
+    }
+
+    fn theme(&self) -> &'static Theme {
+        theme::get(self.theme).unwrap_or_else(theme::seasonal)
+    }
+
+    fn render_label(&self) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        self.label
+            .as_deref()
+            .map(|label| text::render(label, &LABEL_STYLE))
+    }
+}
 
 #[derive(Debug, Clone)]
 /// This struct holds the image in memory
 pub struct CountImage {
     body: Vec<u8>,
+    format: ImageFormat,
 }
 
 impl CountImage {
@@ -16,35 +105,109 @@ impl CountImage {
         self.body.clone()
     }
 
-    /// Returns a new CountImage
-    pub fn total_new() -> Self {
-        let inital_data = match image::open("total.png") {
-            Ok(data) => data.to_rgba8(),
-            Err(_) => image::RgbaImage::new(128, 128),
-        };
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Returns the size of the encoded image in bytes
+    pub fn size(&self) -> usize {
+        self.body.len()
+    }
+
+    /// Returns an empty image, shown until the first image is rendered
+    pub fn placeholder() -> Self {
         CountImage {
-            body: CountImage::img_to_string(&inital_data),
+            body: CountImage::img_to_string(&image::RgbaImage::new(128, 128)),
+            format: ImageFormat::Png,
         }
     }
 
+    /// Read an image that was saved with `save`
+    pub fn load(path: &str) -> Option<Self> {
+        let body = std::fs::read(path).ok()?;
+        image::load_from_memory_with_format(&body, image::ImageFormat::Png).ok()?;
+        Some(CountImage {
+            body,
+            format: ImageFormat::Png,
+        })
+    }
+
     /// Returns a new CountImage
     pub fn total_from_count(count: u128) -> Self {
         let data = CountImage::create_total_image(count);
         let body = CountImage::img_to_string(&data);
-        CountImage { body }
+        CountImage {
+            body,
+            format: ImageFormat::Png,
+        }
     }
 
-    /// Returns a new CountImage
-    pub fn from_count(count: u128) -> Self {
-        let data = CountImage::create_count_image(count);
+    /// Returns a new CountImage in the given format and style
+    pub fn from_count(count: u128, format: ImageFormat, style: &CountStyle) -> Self {
+        let theme = style.theme();
+        let label = style.render_label();
+        if format == ImageFormat::Svg {
+            let (width, height) = CountImage::count_size(count, theme, label.as_ref());
+            let body =
+                CountImage::create_svg(width, height, None, count, (0, 0), theme, label.as_ref());
+            return CountImage { body, format };
+        }
+
+        let mut data = CountImage::create_count_image(count, theme);
+        if let Some(label) = &label {
+            data = CountImage::add_label(&data, label);
+        }
+        let body = CountImage::encode(&data, format);
+        CountImage { body, format }
+    }
+
+    /// Returns a new total image as SVG, with the same layout as the PNG
+    pub fn total_svg_from_count(count: u128) -> Self {
+        let base = season_images::seasonal_count_total();
+        let theme = theme::seasonal();
+        let (_, number_height) = theme.number_size(&count.to_string());
+        let pos = (560, base.height().saturating_sub(number_height) / 2);
+        let body = CountImage::create_svg(
+            base.width(),
+            base.height(),
+            Some(&base),
+            count,
+            pos,
+            theme,
+            None,
+        );
+        CountImage {
+            body,
+            format: ImageFormat::Svg,
+        }
+    }
+
+    /// Returns the same image in another format
+    pub fn convert(&self, format: ImageFormat) -> Option<Self> {
+        if format == self.format {
+            return Some(self.clone());
+        }
+        let data = image::load_from_memory_with_format(&self.body, image::ImageFormat::Png)
+            .ok()?
+            .to_rgba8();
+        let body = CountImage::encode(&data, format);
+        Some(CountImage { body, format })
+    }
+
+    /// Returns a new CountImage with a chart of the downloads per day
+    pub fn from_history(first_day: NaiveDate, downloads: &[Option<u64>]) -> Self {
+        let data = chart::create_history_chart(first_day, downloads);
         let body = CountImage::img_to_string(&data);
-        CountImage { body }
+        CountImage {
+            body,
+            format: ImageFormat::Png,
+        }
     }
 
     /// Render the total image
     fn create_total_image(count: u128) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
         // Generate number
-        let number = CountImage::create_count_image(count);
+        let number = CountImage::create_count_image(count, theme::seasonal());
 
         // Get the seasonal image
         let mut base = season_images::seasonal_count_total();
@@ -55,41 +218,184 @@ impl CountImage {
         base
     }
 
-    fn create_count_image(count: u128) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    fn create_count_image(count: u128, theme: &Theme) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
         // Convert count to char[]
         let count_str = count.to_string();
-        let count_len = count_str.len();
         let count_arr = count_str.chars();
 
-        // Create a new image with the same dimensions as the template
-        let number_width = const_image::NUMBERS[0].width();
-        let number_height = const_image::NUMBERS[0].height();
-        let mut overlay = image::RgbaImage::new(number_width * count_len as u32, number_height);
+        // Create a new image that fits every digit of the theme
+        let (width, height) = theme.number_size(&count_str);
+        let mut overlay = image::RgbaImage::new(width, height);
 
         // Write the Count onto the image
-        for (i, digit) in count_arr.enumerate() {
+        let mut x = 0;
+        for digit in count_arr {
             let digit = digit.to_digit(10).unwrap() as usize;
-            let number = &const_image::NUMBERS[digit];
-            image::imageops::overlay(&mut overlay, number, (i as u32 * number_width) as i64, 0);
+            let number = theme.digit(digit);
+            let y = (height - number.height()) / 2;
+            image::imageops::overlay(&mut overlay, number, x as i64, y as i64);
+            x += number.width();
         }
         overlay
     }
 
-    // Save to Image to the disk (not really needed but nice for debugging)
-    /*
-        pub fn save_image(&self) {
-            let file = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open("image.png")
-                .expect("Cant open image to write to it");
-            let mut bufferd = std::io::BufWriter::new(file);
-            self.data
-                .write_to(&mut bufferd, image::ImageOutputFormat::Png)
-                .expect("Cant write to image");
-            bufferd.flush().expect("Cant flush image");
+    /// Returns the size of a count image with an optional label
+    fn count_size(
+        count: u128,
+        theme: &Theme,
+        label: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    ) -> (u32, u32) {
+        let (width, height) = theme.number_size(&count.to_string());
+        match label {
+            Some(label) => (
+                width + LABEL_GAP + label.width(),
+                height.max(label.height()),
+            ),
+            None => (width, height),
+        }
+    }
+
+    /// Returns the position of the number and of the label in a count image, centered vertically
+    fn label_layout(
+        number: (u32, u32),
+        label: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ) -> ((u32, u32), (u32, u32)) {
+        let height = number.1.max(label.height());
+        (
+            (0, (height - number.1) / 2),
+            (number.0 + LABEL_GAP, (height - label.height()) / 2),
+        )
+    }
+
+    /// Place a label to the right of a number
+    fn add_label(
+        number: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+        label: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let (number_pos, label_pos) = CountImage::label_layout(number.dimensions(), label);
+        let mut img = image::RgbaImage::new(
+            number.width() + LABEL_GAP + label.width(),
+            number.height().max(label.height()),
+        );
+        image::imageops::overlay(&mut img, number, number_pos.0 as i64, number_pos.1 as i64);
+        image::imageops::overlay(&mut img, label, label_pos.0 as i64, label_pos.1 as i64);
+        img
+    }
+
+    /// Compose an SVG of an optional background with the number at `pos`, and an optional label.
+    /// Every digit is embedded once and placed with `<use>`.
+    fn create_svg(
+        width: u32,
+        height: u32,
+        background: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+        count: u128,
+        pos: (u32, u32),
+        theme: &Theme,
+        label: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    ) -> Vec<u8> {
+        let count_str = count.to_string();
+        let (number_width, number_height) = theme.number_size(&count_str);
+        let (pos, label_pos) = match label {
+            Some(label) => {
+                let number = (number_width, number_height);
+                let (number_pos, label_pos) = CountImage::label_layout(number, label);
+                (
+                    (pos.0 + number_pos.0, pos.1 + number_pos.1),
+                    Some(label_pos),
+                )
+            }
+            None => (pos, None),
+        };
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}"><defs>"#,
+            width, height
+        );
+        let mut used = [false; 10];
+        for digit in count_str.chars() {
+            let digit = digit.to_digit(10).unwrap() as usize;
+            if !std::mem::replace(&mut used[digit], true) {
+                let sprite = theme.digit(digit);
+                svg += &format!(
+                    r#"<image id="d{}" width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+                    digit,
+                    sprite.width(),
+                    sprite.height(),
+                    theme.sprite(digit)
+                );
+            }
+        }
+        svg += "</defs>";
+
+        if let Some(background) = background {
+            svg += &format!(
+                r#"<image width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+                background.width(),
+                background.height(),
+                BASE64_STANDARD.encode(CountImage::img_to_string(background))
+            );
+        }
+        let mut x = pos.0;
+        for digit in count_str.chars() {
+            let sprite = theme.digit(digit.to_digit(10).unwrap() as usize);
+            svg += &format!(
+                r##"<use href="#d{}" x="{}" y="{}"/>"##,
+                digit,
+                x,
+                pos.1 + (number_height - sprite.height()) / 2
+            );
+            x += sprite.width();
+        }
+        if let (Some(label), Some((x, y))) = (label, label_pos) {
+            svg += &format!(
+                r#"<image x="{}" y="{}" width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+                x,
+                y,
+                label.width(),
+                label.height(),
+                BASE64_STANDARD.encode(CountImage::img_to_string(label))
+            );
+        }
+        svg += "</svg>";
+        svg.into_bytes()
+    }
+
+    /// Use an image that was already encoded
+    pub fn from_encoded(body: Vec<u8>, format: ImageFormat) -> Option<Self> {
+        format.matches(&body).then_some(CountImage { body, format })
+    }
+
+    /// Save the image to the disk, replacing the file at once
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, &self.body)?;
+        std::fs::rename(&temporary, path)
+    }
+
+    /// Encode the image in the given format
+    fn encode(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, format: ImageFormat) -> Vec<u8> {
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        match format {
+            ImageFormat::Png => return CountImage::img_to_string(img),
+            ImageFormat::Svg => {
+                // Embed the PNG for images that are not composed of sprites
+                return format!(
+                    r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}"><image width="{0}" height="{1}" href="data:image/png;base64,{2}"/></svg>"#,
+                    img.width(),
+                    img.height(),
+                    BASE64_STANDARD.encode(CountImage::img_to_string(img))
+                )
+                .into_bytes();
+            }
+            // The default speed takes seconds even for small images
+            ImageFormat::Avif => img
+                .write_with_encoder(AvifEncoder::new_with_speed_quality(&mut buffer, 8, 80))
+                .unwrap(),
+            ImageFormat::Webp => img.write_to(&mut buffer, image::ImageFormat::WebP).unwrap(),
+            ImageFormat::Gif => img.write_to(&mut buffer, image::ImageFormat::Gif).unwrap(),
         }
-    */
+        buffer.into_inner()
+    }
 
     /// Convert the image to a Vec<u8>
     fn img_to_string(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Vec<u8> {
@@ -105,6 +411,86 @@ impl CountImage {
 
 impl Default for CountImage {
     fn default() -> Self {
-        Self::total_new()
+        Self::placeholder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_every_format() {
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Webp,
+            ImageFormat::Gif,
+            ImageFormat::Avif,
+            ImageFormat::Svg,
+        ] {
+            let img = CountImage::from_count(123, format, &CountStyle::default());
+            assert!(format.matches(&img.body), "{:?}", format);
+            assert!(CountImage::total_from_count(123).convert(format).is_some());
+        }
+    }
+
+    #[test]
+    fn composes_svg_from_digits() {
+        let style = CountStyle::default();
+        let svg =
+            String::from_utf8(CountImage::from_count(1011, ImageFormat::Svg, &style).body).unwrap();
+        assert_eq!(svg.matches("<image").count(), 2);
+        assert_eq!(svg.matches("<use").count(), 4);
+        assert!(svg.contains(r##"<use href="#d1" x="204" y="0"/>"##));
+
+        let total = String::from_utf8(CountImage::total_svg_from_count(7).body).unwrap();
+        assert!(total
+            .starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="1172" height="180""#));
+        assert!(total.contains(r##"<use href="#d7" x="560" y="15"/>"##));
+    }
+
+    #[test]
+    fn places_labels_next_to_numbers() {
+        let default = Some(theme::DEFAULT);
+        assert_eq!(
+            CountStyle::new(Some(" ".to_string()), default),
+            Ok(CountStyle::default())
+        );
+        assert_eq!(
+            CountStyle::new(Some("a".repeat(MAX_LABEL_LENGTH + 1)), default),
+            Err("Invalid label")
+        );
+        assert_eq!(
+            CountStyle::new(Some("images\nshown".to_string()), default),
+            Err("Invalid label")
+        );
+        assert_eq!(CountStyle::default().cache_key(), "");
+
+        let style = CountStyle::new(Some("images shown".to_string()), default).unwrap();
+        assert!(style.cache_key().starts_with(":label-"));
+        let (label_width, _) = text::measure("images shown", LABEL_STYLE.size);
+        let img = CountImage::from_count(42, ImageFormat::Png, &style);
+        let img = image::load_from_memory(&img.body).unwrap();
+        assert_eq!(img.width(), 2 * 68 + LABEL_GAP + label_width + 6);
+        assert_eq!(img.height(), 150);
+
+        let svg = String::from_utf8(CountImage::from_count(42, ImageFormat::Svg, &style).body);
+        assert_eq!(svg.unwrap().matches("<image").count(), 3);
+    }
+
+    #[test]
+    fn draws_digits_of_themes() {
+        assert_eq!(CountStyle::new(None, Some("unknown")), Err("Unknown theme"));
+        let style = CountStyle::new(None, Some("pixel")).unwrap();
+        assert_eq!(style.cache_key(), ":theme-pixel");
+
+        let img = CountImage::from_count(42, ImageFormat::Png, &style);
+        let img = image::load_from_memory(&img.body).unwrap();
+        assert_eq!((img.width(), img.height()), (2 * 66, 100));
+
+        let svg = CountImage::from_count(1011, ImageFormat::Svg, &style).body;
+        assert!(String::from_utf8(svg)
+            .unwrap()
+            .contains(r##"<use href="#d1" x="132" y="0"/>"##));
     }
 }
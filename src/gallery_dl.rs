@@ -5,9 +5,10 @@ use sha2::{Digest, Sha256};
 use std::env;
 use std::time::Duration;
 use tokio::time;
-use warp::http::Response;
 use warp::hyper::StatusCode;
 
+use crate::json::{json_error, json_response};
+
 const DEFAULT_CACHE_TTL_SECONDS: u64 = 15 * 60;
 const LOCK_TTL_SECONDS: u64 = 30;
 const MAX_TAGS: usize = 20;
@@ -26,11 +27,6 @@ pub struct GalleryQuery {
     limit: Option<u16>,
 }
 
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
 #[derive(Serialize)]
 struct WorkerRequest<'a> {
     url: &'a str,
@@ -487,36 +483,6 @@ async fn run_gallery_dl(url: &str, limit: u16) -> Result<serde_json::Value, &'st
     serde_json::from_slice(&bytes).map_err(|_| "invalid gallery-dl output")
 }
 
-fn json_response<T: Serialize>(
-    status: StatusCode,
-    value: &T,
-    server_cache: Option<(&str, u64)>,
-) -> Response<String> {
-    let body = serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string());
-    let mut builder = Response::builder()
-        .status(status)
-        .header("Content-Type", "application/json")
-        .header("Cache-Control", "no-store");
-
-    if let Some((cache_state, ttl)) = server_cache {
-        builder = builder
-            .header("X-Server-Cache", cache_state)
-            .header("X-Server-Cache-Ttl-Seconds", ttl.to_string());
-    }
-
-    builder.body(body).unwrap()
-}
-
-fn json_error(status: StatusCode, message: &str) -> Response<String> {
-    json_response(
-        status,
-        &ErrorResponse {
-            error: message.to_string(),
-        },
-        None,
-    )
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
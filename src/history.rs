@@ -0,0 +1,541 @@
+use chrono::{DateTime, Days, NaiveDate, SecondsFormat, Utc};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use warp::http::Response;
+use warp::hyper::StatusCode;
+use warp::Reply;
+
+use crate::json::{json_error, json_response};
+use crate::{sources, IMAGE_CACHE};
+
+const DEFAULT_DAYS: u64 = 30;
+const DEFAULT_GROWTH_DAYS: u64 = 7;
+const MAX_DAYS: u64 = 366;
+const DEFAULT_HOURLY_RETENTION_DAYS: u64 = 14;
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    from: Option<String>,
+    to: Option<String>,
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChartQuery {
+    days: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct SourcesQuery {
+    days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct GrowthResponse {
+    from: String,
+    to: String,
+    sources: Vec<SourceGrowth>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SourceGrowth {
+    source: String,
+    start: Option<u64>,
+    end: Option<u64>,
+    growth: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SeriesResponse {
+    source: String,
+    points: Vec<SeriesPoint>,
+}
+
+#[derive(Serialize)]
+struct SeriesPoint {
+    time: String,
+    count: u64,
+    resolution: &'static str,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    from: String,
+    to: String,
+    days: Vec<HistoryDay>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct HistoryDay {
+    date: String,
+    total: Option<u64>,
+    delta: Option<i64>,
+    gap: bool,
+}
+
+/// Redis key of the snapshot for a day
+pub fn key(date: NaiveDate) -> String {
+    format!("history:{}", date.format("%Y%m%d"))
+}
+
+/// Redis key of the hourly points of a source
+pub fn hourly_key(source: &str) -> String {
+    format!("history:hourly:{}", source)
+}
+
+/// Redis key of the daily points of a source
+pub fn daily_key(source: &str) -> String {
+    format!("history:daily:{}", source)
+}
+
+/// Sorted set members must be unique, so the timestamp is part of the member
+fn point(timestamp: i64, count: u64) -> String {
+    format!("{}:{}", timestamp, count)
+}
+
+fn parse_point(member: &str) -> Option<(i64, u64)> {
+    let (timestamp, count) = member.split_once(':')?;
+    Some((timestamp.parse().ok()?, count.parse().ok()?))
+}
+
+fn hourly_retention_days() -> u64 {
+    env::var("HISTORY_HOURLY_RETENTION_DAYS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_HOURLY_RETENTION_DAYS)
+}
+
+/// Save the daily total and an hourly point for every source
+pub async fn record(
+    redis: &mut ConnectionManager,
+    total: u64,
+    counts: &[(String, u64)],
+    now: DateTime<Utc>,
+) -> Result<(), redis::RedisError> {
+    let hour = now.timestamp() - now.timestamp().rem_euclid(HOUR);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.set(key(now.date_naive()), total).ignore();
+    for (source, count) in counts {
+        // Replace the point of this hour if the task already ran
+        let series = hourly_key(source);
+        pipe.zrembyscore(&series, hour, hour).ignore();
+        pipe.zadd(&series, point(hour, *count), hour).ignore();
+    }
+    pipe.query_async(redis).await
+}
+
+/// Fold hourly points older than the retention into one point per day.
+/// Returns the number of hourly points that were removed.
+pub async fn downsample(
+    redis: &mut ConnectionManager,
+    sources: &[&str],
+    now: DateTime<Utc>,
+) -> Result<usize, redis::RedisError> {
+    let cutoff = format!(
+        "({}",
+        now.timestamp() - hourly_retention_days() as i64 * DAY
+    );
+    let mut folded = 0;
+
+    for source in sources {
+        let series = hourly_key(source);
+        let members: Vec<String> = redis.zrangebyscore(&series, "-inf", &cutoff).await?;
+        if members.is_empty() {
+            continue;
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let daily = daily_key(source);
+        for (day, count) in daily_points(members.iter().filter_map(|m| parse_point(m))) {
+            pipe.zrembyscore(&daily, day, day).ignore();
+            pipe.zadd(&daily, point(day, count), day).ignore();
+        }
+        pipe.zrembyscore(&series, "-inf", &cutoff).ignore();
+        pipe.query_async::<()>(redis).await?;
+
+        folded += members.len();
+    }
+
+    Ok(folded)
+}
+
+/// Keep the last point of every day, keyed by the start of the day
+fn daily_points(points: impl Iterator<Item = (i64, u64)>) -> Vec<(i64, u64)> {
+    let mut days: BTreeMap<i64, (i64, u64)> = BTreeMap::new();
+    for (timestamp, count) in points {
+        let day = timestamp - timestamp.rem_euclid(DAY);
+        let entry = days.entry(day).or_insert((timestamp, count));
+        if timestamp >= entry.0 {
+            *entry = (timestamp, count);
+        }
+    }
+    days.into_iter()
+        .map(|(day, (_, count))| (day, count))
+        .collect()
+}
+
+/// Returns how much every source grew over the last days, fastest first
+pub async fn growth(
+    request: SourcesQuery,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let days = request.days.unwrap_or(DEFAULT_GROWTH_DAYS);
+    if days == 0 || days > MAX_DAYS {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "invalid days"));
+    }
+    let to = Utc::now();
+    let from = to - chrono::Duration::days(days as i64);
+
+    // Latest hourly and daily point at the start and end of the window
+    let names = sources::listed_names();
+    let mut pipe = redis::pipe();
+    for source in names.iter() {
+        for time in [from.timestamp(), to.timestamp()] {
+            pipe.zrevrangebyscore_limit(hourly_key(source), time, "-inf", 0, 1);
+            pipe.zrevrangebyscore_limit(daily_key(source), time, "-inf", 0, 1);
+        }
+    }
+    let results: Vec<Vec<String>> = match pipe.query_async(&mut redis).await {
+        Ok(results) => results,
+        Err(_) => {
+            log::error!(target: "history", "Failed to get history from Redis");
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database unavailable",
+            ));
+        }
+    };
+
+    let latest = |points: &[Vec<String>]| {
+        points
+            .iter()
+            .flatten()
+            .filter_map(|member| parse_point(member))
+            .max()
+            .map(|(_, count)| count)
+    };
+    let mut growth: Vec<SourceGrowth> = names
+        .into_iter()
+        .zip(results.chunks(4))
+        .map(|(source, points)| {
+            let (start, end) = (latest(&points[..2]), latest(&points[2..]));
+            SourceGrowth {
+                source,
+                start,
+                end,
+                growth: end.map(|end| end.saturating_sub(start.unwrap_or(0))),
+            }
+        })
+        .collect();
+    growth.sort_by_key(|source| std::cmp::Reverse(source.growth));
+
+    Ok(json_response(
+        StatusCode::OK,
+        &GrowthResponse {
+            from: from.to_rfc3339_opts(SecondsFormat::Secs, true),
+            to: to.to_rfc3339_opts(SecondsFormat::Secs, true),
+            sources: growth,
+        },
+        None,
+    ))
+}
+
+/// Returns the hourly and daily points of a source over the last days
+pub async fn series(
+    source: String,
+    request: SourcesQuery,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !sources::is_source(&source) {
+        return Ok(json_error(StatusCode::NOT_FOUND, "unknown source"));
+    }
+    let days = request.days.unwrap_or(DEFAULT_GROWTH_DAYS);
+    if days == 0 || days > MAX_DAYS {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "invalid days"));
+    }
+    let from = (Utc::now() - chrono::Duration::days(days as i64)).timestamp();
+
+    let mut pipe = redis::pipe();
+    pipe.zrangebyscore(daily_key(&source), from, "+inf");
+    pipe.zrangebyscore(hourly_key(&source), from, "+inf");
+    let (daily, hourly): (Vec<String>, Vec<String>) = match pipe.query_async(&mut redis).await {
+        Ok(results) => results,
+        Err(_) => {
+            log::error!(target: "history", "Failed to get history from Redis");
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database unavailable",
+            ));
+        }
+    };
+
+    let mut points: Vec<(i64, u64, &'static str)> = daily
+        .iter()
+        .filter_map(|member| parse_point(member))
+        .map(|(timestamp, count)| (timestamp, count, "day"))
+        .chain(
+            hourly
+                .iter()
+                .filter_map(|member| parse_point(member))
+                .map(|(timestamp, count)| (timestamp, count, "hour")),
+        )
+        .collect();
+    points.sort();
+
+    let points = points
+        .into_iter()
+        .filter_map(|(timestamp, count, resolution)| {
+            Some(SeriesPoint {
+                time: DateTime::from_timestamp(timestamp, 0)?
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+                count,
+                resolution,
+            })
+        })
+        .collect();
+
+    Ok(json_response(
+        StatusCode::OK,
+        &SeriesResponse { source, points },
+        None,
+    ))
+}
+
+/// Returns the daily totals between two dates as JSON or CSV
+pub async fn query(
+    request: HistoryQuery,
+    mut redis: ConnectionManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let csv = match request.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => return Ok(json_error(StatusCode::BAD_REQUEST, "invalid format")),
+    };
+    let (from, to) = match date_range(request.from.as_deref(), request.to.as_deref()) {
+        Ok(range) => range,
+        Err(message) => return Ok(json_error(StatusCode::BAD_REQUEST, message)),
+    };
+
+    let days = match daily_totals(&mut redis, from, to).await {
+        Ok(values) => history_days(from, &values),
+        Err(_) => {
+            log::error!(target: "history", "Failed to get history from Redis");
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database unavailable",
+            ));
+        }
+    };
+
+    if csv {
+        return Ok(Response::builder()
+            .header("Content-Type", "text/csv")
+            .header("Cache-Control", "no-store")
+            .body(to_csv(&days))
+            .unwrap());
+    }
+
+    Ok(json_response(
+        StatusCode::OK,
+        &HistoryResponse {
+            from: from.format(DATE_FORMAT).to_string(),
+            to: to.format(DATE_FORMAT).to_string(),
+            days,
+        },
+        None,
+    ))
+}
+
+/// Returns a chart of the downloads per day as PNG
+pub async fn chart(
+    request: ChartQuery,
+    mut redis: ConnectionManager,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let days = request.days.unwrap_or(DEFAULT_DAYS);
+    if days == 0 || days > MAX_DAYS {
+        return Ok(json_error(StatusCode::BAD_REQUEST, "invalid days").into_response());
+    }
+
+    let to = Utc::now().date_naive();
+    let img = match IMAGE_CACHE.get_history(to, days).await {
+        Some(img) => img,
+        None => {
+            let from = to - Days::new(days - 1);
+            let values = match daily_totals(&mut redis, from, to).await {
+                Ok(values) => values,
+                Err(_) => {
+                    log::error!(target: "history", "Failed to get history from Redis");
+                    return Ok(
+                        json_error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+                            .into_response(),
+                    );
+                }
+            };
+            let downloads: Vec<Option<u64>> = history_days(from, &values)
+                .iter()
+                .map(|day| day.delta.map(|delta| delta.max(0) as u64))
+                .collect();
+
+            let Ok(img) = IMAGE_CACHE.render_history(from, downloads).await else {
+                log::error!(target: "history", "Failed to render the history chart");
+                return Ok(
+                    json_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to render chart")
+                        .into_response(),
+                );
+            };
+            IMAGE_CACHE.insert_history(to, days, img.clone()).await;
+            img
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", "image/png")
+        .header("Cache-Control", "no-cache")
+        .body(img.get_image())
+        .unwrap()
+        .into_response())
+}
+
+/// Get the snapshots from the day before `from` up to `to`
+async fn daily_totals(
+    redis: &mut ConnectionManager,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Option<u64>>, redis::RedisError> {
+    let first = from.pred_opt().unwrap_or(from);
+    let keys: Vec<String> = first
+        .iter_days()
+        .take_while(|date| *date <= to)
+        .map(key)
+        .collect();
+    let values: Vec<Option<u64>> = redis.mget(&keys).await?;
+
+    if first == from {
+        return Ok([&[None], &values[..]].concat());
+    }
+    Ok(values)
+}
+
+fn date_range(
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<(NaiveDate, NaiveDate), &'static str> {
+    let parse =
+        |date: &str| NaiveDate::parse_from_str(date, DATE_FORMAT).map_err(|_| "invalid date");
+
+    let to = match to {
+        Some(to) => parse(to)?,
+        None => Utc::now().date_naive(),
+    };
+    let from = match from {
+        Some(from) => parse(from)?,
+        None => to
+            .checked_sub_days(Days::new(DEFAULT_DAYS - 1))
+            .ok_or("invalid date")?,
+    };
+
+    if from > to {
+        return Err("from must not be after to");
+    }
+    if (to - from).num_days() as u64 >= MAX_DAYS {
+        return Err("date range too large");
+    }
+    Ok((from, to))
+}
+
+/// Build the list of days starting at `from`. The first value belongs to the day before `from`.
+fn history_days(from: NaiveDate, values: &[Option<u64>]) -> Vec<HistoryDay> {
+    from.iter_days()
+        .zip(values.windows(2))
+        .map(|(date, pair)| HistoryDay {
+            date: date.format(DATE_FORMAT).to_string(),
+            total: pair[1],
+            delta: match (pair[0], pair[1]) {
+                (Some(previous), Some(current)) => Some(current as i64 - previous as i64),
+                _ => None,
+            },
+            gap: pair[1].is_none(),
+        })
+        .collect()
+}
+
+fn to_csv(days: &[HistoryDay]) -> String {
+    let mut csv = String::from("date,total,delta,gap\n");
+    for day in days {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            day.date,
+            day.total.map(|t| t.to_string()).unwrap_or_default(),
+            day.delta.map(|d| d.to_string()).unwrap_or_default(),
+            day.gap
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, DATE_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn marks_gaps_and_deltas() {
+        let days = history_days(
+            date("2026-01-01"),
+            &[Some(10), Some(15), None, Some(30), Some(32)],
+        );
+
+        assert_eq!(days.len(), 4);
+        assert_eq!(days[0].date, "2026-01-01");
+        assert_eq!(days[0].delta, Some(5));
+        assert!(days[1].gap);
+        assert_eq!(days[1].total, None);
+        assert_eq!(days[2].delta, None);
+        assert_eq!(days[3].delta, Some(2));
+        assert_eq!(
+            to_csv(&days[1..3]),
+            "date,total,delta,gap\n2026-01-02,,,true\n2026-01-03,30,,false\n"
+        );
+    }
+
+    #[test]
+    fn keeps_last_point_of_each_day() {
+        let points = [
+            (DAY + HOUR, 10),
+            (DAY + 23 * HOUR, 25),
+            (DAY + 5 * HOUR, 12),
+            (2 * DAY, 30),
+        ];
+
+        assert_eq!(
+            daily_points(points.into_iter()),
+            vec![(DAY, 25), (2 * DAY, 30)]
+        );
+        assert_eq!(parse_point(&point(DAY, 42)), Some((DAY, 42)));
+        assert_eq!(parse_point("invalid"), None);
+    }
+
+    #[test]
+    fn validates_date_range() {
+        assert_eq!(
+            date_range(Some("2026-01-01"), Some("2026-02-01")),
+            Ok((date("2026-01-01"), date("2026-02-01")))
+        );
+        assert!(date_range(Some("2026-02-01"), Some("2026-01-01")).is_err());
+        assert!(date_range(Some("2024-01-01"), Some("2026-01-01")).is_err());
+        assert!(date_range(Some("yesterday"), None).is_err());
+    }
+}
@@ -1,59 +1,445 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::future::{BoxFuture, FutureExt, Shared};
 use log::{debug, info, warn};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
 use std::{collections::HashMap, time::Duration};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
 
+use crate::count_image::{self, CountStyle};
+use crate::image_format::ImageFormat;
+use crate::lru::{CacheStats, LruCache};
+use crate::season_images;
+use crate::sources;
 use crate::CountImage;
 
 pub const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
-pub const MAX_CACHE_SIZE: usize = 25;
+/// Time to wait for more changes before rendering the images after the total changed
+pub const REFRESH_DELAY: Duration = Duration::from_secs(1);
+/// Shortest time between two renders of the images after the total changed
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// Default limits of the cache of count images
+const DEFAULT_COUNT_CACHE_ENTRIES: usize = 1000;
+const DEFAULT_COUNT_CACHE_BYTES: usize = 32 * 1024 * 1024;
+const DEFAULT_TOTAL_IMAGE_PATH: &str = "total.png";
+/// Prefix of count images in Redis, followed by the render version
+const REDIS_CACHE_PREFIX: &str = "render";
+/// Default number of renders that can wait for a render thread, including the running renders
+const DEFAULT_RENDER_QUEUE_SIZE: usize = 64;
+
+/// Count and format of a count image
+type CountKey = (u128, ImageFormat, CountStyle);
+
+/// Render of a count image that can be awaited by every request for the same count
+type SharedRender = Shared<BoxFuture<'static, Result<CountImage, RenderError>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RenderError {
+    /// Too many renders are already queued
+    Busy,
+    Failed,
+}
+
+/// Count, seasonal image and render version that an image was rendered with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Rendered {
+    count: u128,
+    season: String,
+    /// Images that were saved before the version was recorded are rendered again
+    #[serde(default)]
+    version: u32,
+}
+
+impl Rendered {
+    fn now(count: u128) -> Self {
+        Rendered {
+            count,
+            season: season_images::seasonal_key(&Utc::now()),
+            version: count_image::RENDER_VERSION,
+        }
+    }
+}
 
-#[derive(Debug)]
 pub struct ImageCache {
     // Image in memory
     count_total_image: Mutex<CountImage>,
 
-    // List of count images
-    count_images: Mutex<HashMap<u128, CountImage>>,
+    // Count and season of the total image, if it was rendered
+    total_rendered: Mutex<Option<Rendered>>,
+
+    // Total image in other formats, with the count and season it was converted from
+    total_formats: Mutex<HashMap<ImageFormat, (Option<Rendered>, CountImage)>>,
+    total_conversion: Mutex<()>,
+
+    // Time of the last total image update
+    last_update: Mutex<Option<DateTime<Utc>>>,
+
+    // Total image of every source
+    source_images: Mutex<HashMap<String, (Rendered, CountImage)>>,
+
+    // Counts of the last update by source name, and the total under `total`
+    counters: Mutex<HashMap<String, u64>>,
+
+    // Recently used count images
+    count_images: Mutex<LruCache<CountKey, CountImage>>,
+
+    // Count images that are being rendered
+    renders: Mutex<HashMap<CountKey, SharedRender>>,
+
+    // Connection to the render cache shared by every instance, if it is enabled
+    redis: OnceLock<(ConnectionManager, u64)>,
+
+    // Limits the number of running and of queued renders
+    render_threads: Semaphore,
+    render_queue: Arc<Semaphore>,
+
+    // History charts by last day and number of days
+    history_images: Mutex<HashMap<(NaiveDate, u64), CountImage>>,
 }
 
 impl ImageCache {
     pub fn new() -> Self {
+        // Start with the total image of the last run
+        let path = total_image_path();
+        let (total, rendered) = match CountImage::load(&path) {
+            Some(img) => {
+                let rendered = std::fs::read_to_string(format!("{}.json", path))
+                    .ok()
+                    .and_then(|state| serde_json::from_str::<Rendered>(&state).ok());
+                (img, rendered)
+            }
+            None => (CountImage::placeholder(), None),
+        };
+
         ImageCache {
-            count_total_image: Mutex::new(CountImage::total_new()),
-            count_images: Mutex::new(HashMap::new()),
+            count_total_image: Mutex::new(total),
+            total_rendered: Mutex::new(rendered),
+            total_formats: Mutex::new(HashMap::new()),
+            total_conversion: Mutex::new(()),
+            last_update: Mutex::new(None),
+            source_images: Mutex::new(HashMap::new()),
+            counters: Mutex::new(HashMap::new()),
+            count_images: Mutex::new(LruCache::new(
+                env_setting("COUNT_CACHE_MAX_ENTRIES", DEFAULT_COUNT_CACHE_ENTRIES),
+                env_setting("COUNT_CACHE_MAX_BYTES", DEFAULT_COUNT_CACHE_BYTES),
+            )),
+            renders: Mutex::new(HashMap::new()),
+            redis: OnceLock::new(),
+            // Renders would wait forever without a thread, and every request would be rejected
+            // without a queue
+            render_threads: Semaphore::new(
+                env_setting(
+                    "RENDER_THREADS",
+                    std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+                )
+                .max(1),
+            ),
+            render_queue: Arc::new(Semaphore::new(
+                env_setting("RENDER_QUEUE_SIZE", DEFAULT_RENDER_QUEUE_SIZE).max(1),
+            )),
+            history_images: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Render the total image, unless the count and the seasonal image did not change
     pub async fn update_total_image(&self, count: u128) {
+        *self.last_update.lock().await = Some(Utc::now());
+        let rendered = Rendered::now(count);
+        if self.total_rendered.lock().await.as_ref() == Some(&rendered) {
+            debug!("Total image is up to date, count: {}", count);
+            return;
+        }
+
         info!("Updating total image, count: {}", count);
-        let mut new_img = CountImage::total_from_count(count);
+        let render = match self.render_threads.acquire().await {
+            Ok(_thread) => {
+                tokio::task::spawn_blocking(move || CountImage::total_from_count(count)).await
+            }
+            Err(_) => return,
+        };
+        let Ok(mut new_img) = render else {
+            warn!("Failed to render total image, count: {}", count);
+            return;
+        };
         let mut img = self.count_total_image.lock().await;
         std::mem::swap(&mut *img, &mut new_img);
+        drop(img);
+        *self.total_rendered.lock().await = Some(rendered);
+        self.save_total().await;
     }
 
-    pub async fn get_total(&self) -> CountImage {
-        (*self.count_total_image.lock().await).clone()
+    /// Save the total image and its count, so it can be served right after a restart
+    pub async fn save_total(&self) {
+        let Some(rendered) = self.total_rendered.lock().await.clone() else {
+            return;
+        };
+        let path = total_image_path();
+        let img = self.count_total_image.lock().await.clone();
+        let state = serde_json::to_string(&rendered).unwrap();
+
+        // Each file is replaced at once, so a restart never reads a partially written file.
+        // The state is written after the image, so a stop in between only renders the image again.
+        let saving = path.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            img.save(&saving)?;
+            let temporary = format!("{}.json.tmp", saving);
+            std::fs::write(&temporary, state)?;
+            std::fs::rename(&temporary, format!("{}.json", saving))
+        })
+        .await
+        .unwrap_or_else(|error| Err(std::io::Error::other(error)));
+        if let Err(error) = saved {
+            warn!("Failed to save total image to {}: {}", path, error);
+        }
     }
 
-    pub async fn get_count(&self, count: u128) -> CountImage {
-        {
-            let map = self.count_images.lock().await;
-            if let Some(img) = map.get(&count) {
-                return img.clone();
+    pub async fn last_update(&self) -> Option<DateTime<Utc>> {
+        *self.last_update.lock().await
+    }
+
+    /// Returns the total image in a format. Other formats than PNG are converted once per update.
+    pub async fn get_total(&self, format: ImageFormat) -> Result<CountImage, RenderError> {
+        // Read the count before the image, it is changed after the image
+        let rendered = self.total_rendered.lock().await.clone();
+        let total = self.count_total_image.lock().await.clone();
+        if format == total.format() {
+            return Ok(total);
+        }
+
+        let _conversion = self.total_conversion.lock().await;
+        if let Some((from, img)) = self.total_formats.lock().await.get(&format) {
+            if *from == rendered {
+                return Ok(img.clone());
             }
         }
 
-        // Release Lock while generating image
-        let img = CountImage::from_count(count);
-        debug!("Generated image for {}", count);
+        // SVG images are composed again from the sprites, other formats are converted
+        let count = rendered.as_ref().map(|rendered| rendered.count);
+        let img = match self.render_threads.acquire().await {
+            Ok(_thread) => tokio::task::spawn_blocking(move || match (format, count) {
+                (ImageFormat::Svg, Some(count)) => Some(CountImage::total_svg_from_count(count)),
+                _ => total.convert(format),
+            })
+            .await
+            .ok()
+            .flatten()
+            .ok_or(RenderError::Failed)?,
+            Err(_) => return Err(RenderError::Failed),
+        };
+        debug!("Converted total image to {}", format.extension());
+        self.total_formats
+            .lock()
+            .await
+            .insert(format, (rendered, img.clone()));
+        Ok(img)
+    }
 
-        let mut map = self.count_images.lock().await;
-        if map.len() >= MAX_CACHE_SIZE {
-            // Cleare the cache
-            warn!("Clearing cache");
-            map.clear();
+    /// Render the total image of a source, unless the count and the seasonal image did not change
+    pub async fn update_source_image(&self, source: &str, count: u128) {
+        let rendered = Rendered::now(count);
+        if let Some((current, _)) = self.source_images.lock().await.get(source) {
+            if *current == rendered {
+                return;
+            }
         }
-        map.insert(count, img.clone());
-        img
+
+        debug!("Updating total image of {}, count: {}", source, count);
+        let render = match self.render_threads.acquire().await {
+            Ok(_thread) => {
+                tokio::task::spawn_blocking(move || CountImage::total_from_count(count)).await
+            }
+            Err(_) => return,
+        };
+        let Ok(img) = render else {
+            warn!(
+                "Failed to render total image of {}, count: {}",
+                source, count
+            );
+            return;
+        };
+        let mut map = self.source_images.lock().await;
+        map.insert(source.to_string(), (rendered, img));
+    }
+
+    /// Returns the total image of a source, or the placeholder if it was not rendered yet
+    pub async fn get_source(&self, source: &str) -> CountImage {
+        let map = self.source_images.lock().await;
+        map.get(source)
+            .map(|(_, img)| img.clone())
+            .unwrap_or_default()
+    }
+
+    /// Store the counts of the last update, replacing those of sources that were removed
+    pub async fn update_counters(&self, total: u64, counts: &[(String, u64)]) {
+        let mut counters: HashMap<String, u64> = counts.iter().cloned().collect();
+        counters.insert(sources::TOTAL_KEY.to_string(), total);
+        *self.counters.lock().await = counters;
     }
+
+    /// Returns the count of a source or of `total`, if it was updated yet
+    pub async fn get_counter(&self, name: &str) -> Option<u64> {
+        self.counters.lock().await.get(name).copied()
+    }
+
+    /// Returns the image of a count. Concurrent requests for the same image share one render.
+    pub async fn get_count(
+        &'static self,
+        count: u128,
+        format: ImageFormat,
+        style: CountStyle,
+    ) -> Result<CountImage, RenderError> {
+        let key = (count, format, style);
+        if let Some(img) = self.count_images.lock().await.get(&key) {
+            return Ok(img.clone());
+        }
+
+        let render = {
+            let mut renders = self.renders.lock().await;
+            match renders.get(&key) {
+                Some(render) => render.clone(),
+                None => {
+                    let Ok(queued) = self.render_queue.clone().try_acquire_owned() else {
+                        warn!("Render queue is full, rejecting image for {}", count);
+                        return Err(RenderError::Busy);
+                    };
+                    // Render in a task, so the render finishes even if every request is cancelled
+                    let render = tokio::spawn(self.render_count(key.clone(), queued))
+                        .map(|result| result.unwrap_or(Err(RenderError::Failed)))
+                        .boxed()
+                        .shared();
+                    renders.insert(key.clone(), render.clone());
+                    render
+                }
+            }
+        };
+        render.await
+    }
+
+    /// Share rendered count images with other instances through Redis.
+    /// Does nothing unless `RENDER_CACHE_TTL_SECONDS` is set.
+    pub fn set_redis(&self, redis: ConnectionManager) {
+        let ttl = env_setting("RENDER_CACHE_TTL_SECONDS", 0) as u64;
+        if ttl > 0 && self.redis.set((redis, ttl)).is_ok() {
+            info!("Sharing rendered images in Redis for {} seconds", ttl);
+        }
+    }
+
+    /// Render a count image on a blocking thread, unless another instance already rendered it,
+    /// and add it to the cache
+    async fn render_count(
+        &'static self,
+        key: CountKey,
+        _queued: OwnedSemaphorePermit,
+    ) -> Result<CountImage, RenderError> {
+        let (count, format, style) = key.clone();
+        let redis_key = format!(
+            "{}:v{}:count:{}{}.{}",
+            REDIS_CACHE_PREFIX,
+            count_image::RENDER_VERSION,
+            count,
+            style.cache_key(),
+            format.extension()
+        );
+        let shared = match self.redis.get() {
+            Some((redis, _)) => {
+                let stored: Option<Vec<u8>> =
+                    redis.clone().get(&redis_key).await.unwrap_or_default();
+                stored.and_then(|body| CountImage::from_encoded(body, format))
+            }
+            None => None,
+        };
+
+        let result = match shared {
+            Some(img) => Ok(img),
+            None => match self.render_threads.acquire().await {
+                Ok(_thread) => tokio::task::spawn_blocking(move || {
+                    CountImage::from_count(count, format, &style)
+                })
+                .await
+                .map_err(|_| RenderError::Failed),
+                Err(_) => Err(RenderError::Failed),
+            }
+            .inspect(|img| self.share(redis_key, img)),
+        };
+
+        match &result {
+            Ok(img) => {
+                debug!("Generated image for {}", count);
+                let size = img.size();
+                self.count_images
+                    .lock()
+                    .await
+                    .insert(key.clone(), img.clone(), size);
+            }
+            Err(_) => warn!("Failed to render image for {}", count),
+        }
+        self.renders.lock().await.remove(&key);
+        result
+    }
+
+    /// Save a rendered image in the render cache of every instance
+    fn share(&self, key: String, img: &CountImage) {
+        let Some((redis, ttl)) = self.redis.get() else {
+            return;
+        };
+        let (mut redis, ttl, body) = (redis.clone(), *ttl, img.get_image());
+        tokio::spawn(async move {
+            let saved: Result<(), _> = redis.set_ex(&key, body, ttl).await;
+            if let Err(error) = saved {
+                debug!("Failed to save {} in Redis: {}", key, error);
+            }
+        });
+    }
+
+    /// Returns the size and the hit, miss and eviction counters of the count image cache
+    pub async fn count_cache_stats(&self) -> CacheStats {
+        self.count_images.lock().await.stats()
+    }
+
+    pub async fn get_history(&self, to: NaiveDate, days: u64) -> Option<CountImage> {
+        let map = self.history_images.lock().await;
+        map.get(&(to, days)).cloned()
+    }
+
+    /// Render a history chart on a render thread
+    pub async fn render_history(
+        &self,
+        first_day: NaiveDate,
+        downloads: Vec<Option<u64>>,
+    ) -> Result<CountImage, RenderError> {
+        match self.render_threads.acquire().await {
+            Ok(_thread) => {
+                tokio::task::spawn_blocking(move || CountImage::from_history(first_day, &downloads))
+                    .await
+                    .map_err(|_| RenderError::Failed)
+            }
+            Err(_) => Err(RenderError::Failed),
+        }
+    }
+
+    pub async fn insert_history(&self, to: NaiveDate, days: u64, img: CountImage) {
+        let mut map = self.history_images.lock().await;
+        map.insert((to, days), img);
+    }
+
+    /// Remove all history charts, called after a new snapshot was saved
+    pub async fn clear_history(&self) {
+        self.history_images.lock().await.clear();
+    }
+}
+
+fn env_setting(variable: &str, default: usize) -> usize {
+    std::env::var(variable)
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+fn total_image_path() -> String {
+    std::env::var("TOTAL_IMAGE_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| DEFAULT_TOTAL_IMAGE_PATH.to_string())
 }
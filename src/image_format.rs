@@ -0,0 +1,151 @@
+/// Formats that count images can be served in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Png,
+    Webp,
+    Gif,
+    Avif,
+    Svg,
+}
+
+/// Formats in the order they are picked if a client accepts several of them equally
+const PREFERENCE: [ImageFormat; 5] = [
+    ImageFormat::Webp,
+    ImageFormat::Png,
+    ImageFormat::Avif,
+    ImageFormat::Gif,
+    ImageFormat::Svg,
+];
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Svg => "svg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        PREFERENCE
+            .into_iter()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    /// Pick the format a client prefers from its `Accept` header.
+    ///
+    /// Only formats that are listed explicitly are picked over PNG, so clients that
+    /// accept `*/*` or `image/*` keep getting PNG.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return ImageFormat::Png;
+        };
+
+        let mut best = (ImageFormat::Png, 0.0);
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';');
+            let media_type = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let format = match media_type {
+                "*/*" | "image/*" => ImageFormat::Png,
+                media_type => match PREFERENCE
+                    .into_iter()
+                    .find(|format| format.content_type().eq_ignore_ascii_case(media_type))
+                {
+                    Some(format) => format,
+                    None => continue,
+                },
+            };
+            if quality > best.1 || (quality == best.1 && format.rank() < best.0.rank()) {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+
+    /// Returns true if `body` starts like an image of this format
+    pub fn matches(self, body: &[u8]) -> bool {
+        match self {
+            ImageFormat::Png => body.starts_with(b"\x89PNG\r\n\x1a\n"),
+            ImageFormat::Webp => body.starts_with(b"RIFF") && body.get(8..12) == Some(b"WEBP"),
+            ImageFormat::Gif => body.starts_with(b"GIF8"),
+            ImageFormat::Avif => body.get(4..8) == Some(b"ftyp"),
+            ImageFormat::Svg => body.starts_with(b"<svg"),
+        }
+    }
+
+    fn rank(self) -> usize {
+        PREFERENCE
+            .iter()
+            .position(|format| *format == self)
+            .unwrap()
+    }
+}
+
+/// Split a path segment like `123.webp` into the name and the format of the extension.
+/// Returns `None` if the extension is not a known format.
+pub fn split_extension(segment: &str) -> Option<(&str, Option<ImageFormat>)> {
+    match segment.rsplit_once('.') {
+        Some((name, extension)) => Some((name, Some(ImageFormat::from_extension(extension)?))),
+        None => Some((segment, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_formats() {
+        assert_eq!(ImageFormat::from_accept(None), ImageFormat::Png);
+        assert_eq!(ImageFormat::from_accept(Some("*/*")), ImageFormat::Png);
+        assert_eq!(
+            ImageFormat::from_accept(Some(
+                "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"
+            )),
+            ImageFormat::Webp
+        );
+        assert_eq!(
+            ImageFormat::from_accept(Some("image/webp;q=0.5, image/gif")),
+            ImageFormat::Gif
+        );
+        assert_eq!(
+            ImageFormat::from_accept(Some("image/avif;q=0.9, */*")),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            ImageFormat::from_accept(Some("text/html, image/jpeg")),
+            ImageFormat::Png
+        );
+    }
+
+    #[test]
+    fn splits_extensions() {
+        assert_eq!(split_extension("123"), Some(("123", None)));
+        assert_eq!(
+            split_extension("123.webp"),
+            Some(("123", Some(ImageFormat::Webp)))
+        );
+        assert_eq!(
+            split_extension("count_total.SVG"),
+            Some(("count_total", Some(ImageFormat::Svg)))
+        );
+        assert_eq!(split_extension("123.jpg"), None);
+    }
+}
@@ -0,0 +1,38 @@
+use serde::Serialize;
+use warp::http::Response;
+use warp::hyper::StatusCode;
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+pub fn json_response<T: Serialize>(
+    status: StatusCode,
+    value: &T,
+    server_cache: Option<(&str, u64)>,
+) -> Response<String> {
+    let body = serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string());
+    let mut builder = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store");
+
+    if let Some((cache_state, ttl)) = server_cache {
+        builder = builder
+            .header("X-Server-Cache", cache_state)
+            .header("X-Server-Cache-Ttl-Seconds", ttl.to_string());
+    }
+
+    builder.body(body).unwrap()
+}
+
+pub fn json_error(status: StatusCode, message: &str) -> Response<String> {
+    json_response(
+        status,
+        &ErrorResponse {
+            error: message.to_string(),
+        },
+        None,
+    )
+}
@@ -1,3 +1,4 @@
+use futures_util::StreamExt;
 use log::info;
 use redis::aio::ConnectionManager;
 use redis::AsyncCommands;
@@ -9,36 +10,40 @@ use std::{
         Arc,
     },
 };
+use tokio::sync::Notify;
 use tokio::time;
-use warp::{http::Response, hyper::StatusCode, reply, Filter};
+use tokio_util::sync::CancellationToken;
+use warp::{
+    http::{HeaderMap, Response},
+    hyper::StatusCode,
+    reply, Filter,
+};
 
 mod count_image;
-use count_image::CountImage;
+use count_image::{CountImage, CountStyle};
 
 mod image_cache;
-use image_cache::ImageCache;
-
+use image_cache::{ImageCache, RenderError};
+use image_format::ImageFormat;
+
+mod add;
+mod admin;
+mod badge;
+mod buffer;
+mod chart;
 mod const_image;
 mod gallery_dl;
+mod history;
+mod image_format;
+mod json;
+mod lru;
+mod rate_limit;
 mod season_images;
+mod stats;
+mod text;
+mod theme;
 
-const IMAGE_SOURCES: [&str; 15] = [
-    "nekos.life",
-    "nekos.best",
-    "nekosapi.com",
-    "nekosia.cat",
-    "pic.re",
-    "purrbot",
-    "shibe.online",
-    "catboys",
-    "waifu.im",
-    "waifu.pics",
-    "dog_ceo",
-    "the_cat_api",
-    "twitter_search",
-    "twitter_user_timeline",
-    "testing",
-];
+mod sources;
 
 lazy_static::lazy_static! {
     // Cache of images
@@ -46,51 +51,131 @@ lazy_static::lazy_static! {
 }
 
 pub async fn init(port: u16) {
-    // Create Logger
-    if env::var_os("RUST_LOG").is_none() {
-        env::set_var("RUST_LOG", "neko_server=info");
-    }
-    if env::var_os("RUST_LOG_STYLE").is_none() {
-        env::set_var("RUST_LOG_STYLE", "never");
-    }
-    env_logger::builder()
-        .format_timestamp(None)
-        .target(env_logger::Target::Stdout)
-        .init();
+    init_logger();
 
     // Database connection
-    let redis_url: &str = &env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let redis_client = redis::Client::open(redis_url).expect("Incorrect Redis URL");
-    let redis = ConnectionManager::new(redis_client)
-        .await
-        .unwrap_or_else(|_| panic!("Failed to connect to Redis at: {}", redis_url));
+    let (redis_client, redis) = connect_redis().await;
     {
         let mut conn = redis.clone();
         let check: Result<(), _> = conn.set("auth_test", "success").await;
         check.expect("Failed to execute redis commands");
+        sources::load(&mut conn)
+            .await
+            .expect("Failed to load image sources");
+
+        // Compute the total once if it was never saved
+        let exists: bool = conn
+            .exists(sources::TOTAL_KEY)
+            .await
+            .expect("Failed to execute redis commands");
+        if !exists {
+            let total = sources::reconcile_total(&mut conn)
+                .await
+                .expect("Failed to compute the total");
+            info!("Computed the total of {} images from the sources", total);
+        }
     }
 
-    // Update the image from the database
+    IMAGE_CACHE.set_redis(redis.clone());
+
+    // Restore increments that were not written to Redis before the last shutdown
+    let restored = buffer::load_journal();
+    if restored > 0 {
+        info!("Restored {} buffered increments from the journal", restored);
+    }
+
+    // Write buffered increments to Redis, either behind every request or once Redis is available again
     let mut redis_clone = redis.clone();
-    let update_task = tokio::spawn(async move {
-        let mut interval = time::interval(image_cache::UPDATE_INTERVAL);
+    let stop_buffer = CancellationToken::new();
+    let stop = stop_buffer.clone();
+    let buffer_task = tokio::spawn(async move {
+        let mut interval = time::interval(buffer::flush_interval());
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut failing = false;
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.cancelled() => break,
+            }
 
-            let mut pipe = redis::pipe();
-            pipe.atomic();
-            for source in IMAGE_SOURCES.iter() {
-                pipe.get(*source);
+            match buffer::flush(&mut redis_clone).await {
+                Ok(written) if failing => {
+                    failing = false;
+                    info!("Wrote {} buffered increments to Redis", written);
+                }
+                Ok(_) => {}
+                Err(_) if !failing => {
+                    failing = true;
+                    log::warn!(
+                        "Failed to write {} buffered increments to Redis, retrying",
+                        buffer::pending()
+                    );
+                }
+                Err(_) => {}
             }
-            let results = pipe.query_async::<Vec<Option<u64>>>(&mut redis_clone).await;
+        }
+    });
 
-            if results.is_err() {
-                log::error!("Failed to get image count from Redis");
-                continue;
+    // Get notified when any instance adds images or changes the sources
+    let refresh = Arc::new(Notify::new());
+    let notify = refresh.clone();
+    let mut redis_clone = redis.clone();
+    let subscribe_task = tokio::spawn(async move {
+        loop {
+            if let Err(error) = watch_changes(&redis_client, &mut redis_clone, &notify).await {
+                log::warn!("Lost the subscription to changes of the total: {}", error);
             }
-            let sum: u64 = results.unwrap().into_iter().flatten().sum();
+            time::sleep(Duration::from_secs(5)).await;
+        }
+    });
 
-            IMAGE_CACHE.update_total_image(sum as u128).await;
+    // Update the image from the database when the total changes, or at least every interval
+    let mut redis_clone = redis.clone();
+    let update_task = tokio::spawn(async move {
+        let mut interval = time::interval(image_cache::UPDATE_INTERVAL);
+        let mut last_refresh = time::Instant::now();
+        loop {
+            // Render the new seasonal image exactly when it changes
+            let now = chrono::Utc::now();
+            let season_change = (season_images::next_change(&now) - now)
+                .to_std()
+                .unwrap_or_default();
+
+            let refreshed = tokio::select! {
+                _ = interval.tick() => false,
+                _ = time::sleep(season_change) => false,
+                _ = refresh.notified() => {
+                    // Collect more changes, and render at most once every refresh interval.
+                    // Notifications that arrive meanwhile are handled by this refresh.
+                    let wait = image_cache::REFRESH_INTERVAL.saturating_sub(last_refresh.elapsed());
+                    time::sleep(wait.max(image_cache::REFRESH_DELAY)).await;
+                    interval.reset();
+                    true
+                }
+            };
+            last_refresh = time::Instant::now();
+
+            // Pick up changes of the sources that were missed while the subscription was lost.
+            // Changes that were published are already loaded by the subscription.
+            if !refreshed && sources::load(&mut redis_clone).await.is_err() {
+                log::error!("Failed to load image sources from Redis");
+            }
+
+            let (total, counts) = match sources::counts(&mut redis_clone).await {
+                Ok(counts) => counts,
+                Err(_) => {
+                    log::error!("Failed to get image count from Redis");
+                    continue;
+                }
+            };
+
+            IMAGE_CACHE.update_counters(total, &counts).await;
+            IMAGE_CACHE.update_total_image(total as u128).await;
+            for (source, count) in counts {
+                IMAGE_CACHE
+                    .update_source_image(&source, count as u128)
+                    .await;
+            }
         }
     });
 
@@ -101,66 +186,64 @@ pub async fn init(port: u16) {
         loop {
             interval.tick().await;
 
-            // Get current sum
-            let mut pipe = redis::pipe();
-            pipe.atomic();
-            for source in IMAGE_SOURCES.iter() {
-                pipe.get(*source);
-            }
-            let results = pipe.query_async::<Vec<Option<u64>>>(&mut redis_clone).await;
-
-            if results.is_err() {
-                log::error!(target: "history", "Failed to get image count from Redis");
+            // Get current total
+            let (total, counts) = match sources::counts(&mut redis_clone).await {
+                Ok(counts) => counts,
+                Err(_) => {
+                    log::error!(target: "history", "Failed to get image count from Redis");
+                    continue;
+                }
+            };
+
+            // Save the daily total and hourly points of every source
+            let now = chrono::Utc::now();
+            if history::record(&mut redis_clone, total, &counts, now)
+                .await
+                .is_err()
+            {
+                log::error!(target: "history", "Failed to save history to Redis");
                 continue;
             }
-            let sum: u64 = results.unwrap().into_iter().flatten().sum();
 
-            // Get Date and Key name
-            let date = chrono::Utc::now().format("%Y%m%d").to_string();
-            let key_name = format!("history:{}", date);
+            IMAGE_CACHE.clear_history().await;
 
-            // Save to Redis
-            let result: Result<(), redis::RedisError> = redis_clone.set(key_name, sum).await;
-            if result.is_err() {
-                log::error!(target: "history", "Failed to save history to Redis");
-                continue;
-            }
+            log::info!(target: "history", "Saved history of {} downloads on {} to Redis", total, now.date_naive());
 
-            log::info!(target: "history", "Saved history of {} downloads on {} to Redis", sum, date);
+            // Fold old hourly points into daily points
+            let names: Vec<&str> = counts.iter().map(|(source, _)| source.as_str()).collect();
+            match history::downsample(&mut redis_clone, &names, now).await {
+                Ok(0) => {}
+                Ok(folded) => {
+                    log::info!(target: "history", "Folded {} hourly points into daily points", folded)
+                }
+                Err(_) => log::error!(target: "history", "Failed to downsample history in Redis"),
+            }
         }
     });
 
     // Print current season
     info!("Current Season: {}", season_images::seasonal_name());
 
-    // Create a filter for each Imagesource
-    let add_routes = IMAGE_SOURCES
-        .iter()
-        .map(|name| {
-            info!("Adding route for {}", name);
-            // Add the path /add/<name_snake>/:count
-            warp::path("add")
-                .and(warp::path(name))
-                .and(warp::post())
-                .and(warp::path::param())
-                .and(warp::header::optional::<String>("User-Agent"))
-                .and(with_redis(redis.clone()))
-                .and_then(move |count, agent, redis| add(name, count, agent, redis))
-                .or(warp::path("add")
-                    .and(warp::path(name))
-                    .and(warp::post())
-                    .and_then(|| async {
-                        Ok::<_, warp::Rejection>(
-                            Response::builder()
-                                .status(StatusCode::NOT_ACCEPTABLE)
-                                .body("Number to large".to_string())
-                                .unwrap(),
-                        )
-                    }))
-                .boxed()
-        })
-        .reduce(|acc, item| acc.or(item).unify().boxed())
-        .expect("No routes were created");
+    info!("Loaded {} image sources", sources::names().len());
+    info!("Loaded digit themes: {}", theme::names().join(", "));
+
+    // Add the path /add/<source>/<count>
+    let add_routes = warp::path!("add" / String / String)
+        .and(warp::post())
+        .and(warp::header::headers_cloned())
+        .and(with_redis(redis.clone()))
+        .and_then(add::add);
+
+    // Add the path /add for multiple sources at once
+    let add_routes = add_routes.or(warp::path!("add")
+        .and(warp::post())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::bytes())
+        .and(with_redis(redis.clone()))
+        .and_then(add::add_batch)
+        .recover(add::reject_batch)
+        .unify());
 
     // Add error message
     let add_routes = add_routes.or(warp::path("add").map(|| {
@@ -170,17 +253,107 @@ pub async fn init(port: u16) {
             .unwrap()
     }));
 
+    // Create a filter for the clients that were rate limited
+    let admin_throttled = warp::path!("admin" / "throttled")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("Authorization"))
+        .and(warp::query::<admin::ThrottledQuery>())
+        .and(with_redis(redis.clone()))
+        .and_then(admin::throttled);
+
+    // Create filters for the image source registry
+    let admin_sources = warp::path!("admin" / "sources")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(admin::list_sources)
+        .or(warp::path!("admin" / "sources")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::content_length_limit(1024))
+            .and(warp::body::json())
+            .and(with_redis(redis.clone()))
+            .and_then(admin::add_source))
+        .or(warp::path!("admin" / "sources" / String / "merge")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::content_length_limit(1024))
+            .and(warp::body::json())
+            .and(with_redis(redis.clone()))
+            .and_then(admin::merge_source))
+        .or(warp::path!("admin" / "sources" / String)
+            .and(warp::patch())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::content_length_limit(1024))
+            .and(warp::body::json())
+            .and(with_redis(redis.clone()))
+            .and_then(admin::update_source));
+
+    // Create a filter to get the total count image of a single source
+    let get_source_image = warp::path!("count_total" / String)
+        .and(warp::get())
+        .and_then(get_source_image);
+
     // Create a filter to get the total count image
     let get_image = warp::path("count_total")
         .and(warp::get())
+        .and(warp::header::optional::<String>("Accept"))
         .and_then(get_total_image);
 
-    // Create a filter to get the image for a specific count
+    // Create a filter to get the total count image with an extension like /count_total.webp.
+    // Other single segment paths do not parse as the file name and fall through to the next routes.
+    let get_image_file = warp::path::param::<TotalImageFile>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_total_image_file);
+
+    // Create a filter to get the image for a specific count, optionally with an extension
     let get_count = warp::path("count")
         .and(warp::get())
-        .and(warp::path::param())
+        .and(warp::path::param::<String>())
+        .and(warp::header::optional::<String>("Accept"))
+        .and(warp::query::<CountQuery>())
         .and_then(get_count_image);
 
+    // Create a filter for badges like /badge/total.svg and /badge/<source>.json
+    let badge = warp::path!("badge" / String)
+        .and(warp::get())
+        .and_then(badge::badge);
+
+    // Create a filter for the counter statistics
+    let stats = warp::path("stats")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_redis(redis.clone()))
+        .and_then(stats::stats);
+
+    // Create a filter for the daily download history
+    let history = warp::path("history")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<history::HistoryQuery>())
+        .and(with_redis(redis.clone()))
+        .and_then(history::query);
+
+    // Create filters for the growth and time series of every source
+    let history_growth = warp::path!("history" / "sources")
+        .and(warp::get())
+        .and(warp::query::<history::SourcesQuery>())
+        .and(with_redis(redis.clone()))
+        .and_then(history::growth);
+    let history_series = warp::path!("history" / "sources" / String)
+        .and(warp::get())
+        .and(warp::query::<history::SourcesQuery>())
+        .and(with_redis(redis.clone()))
+        .and_then(history::series);
+
+    // Create a filter for the download history chart
+    let history_chart = warp::path("history.png")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<history::ChartQuery>())
+        .and(with_redis(redis.clone()))
+        .and_then(history::chart);
+
     // Create a filter for the favicon
     let favicon = warp::path("favicon.ico")
         .and(warp::get())
@@ -216,23 +389,26 @@ pub async fn init(port: u16) {
     });
 
     // Combine all Filters
-    let routes = get_image
+    let routes = get_source_image
+        .or(get_image)
+        .or(get_image_file)
         .or(add_routes)
         .or(get_count)
+        .or(badge)
+        .or(stats)
+        .or(admin_sources)
+        .or(admin_throttled)
+        .or(history)
+        .or(history_growth)
+        .or(history_series)
+        .or(history_chart)
         .or(gallery_query)
         .or(index)
         .or(favicon);
 
     // Add logger
     let routes = routes.with(warp::log::custom(|info| {
-        // Look for the `x-forwarded-for` header, and if it's not present, fall back to x-real-ip, and if that's not present, fall back to the remote addr.
-        let ip: String = info
-            .request_headers()
-            .get("x-forwarded-for")
-            .or_else(|| info.request_headers().get("x-real-ip"))
-            .map(|ip| ip.to_str().unwrap_or("Invalid Header"))
-            .unwrap_or_else(|| "Unknown")
-            .to_owned();
+        let ip = client_ip(info.request_headers());
 
         log::info!(
             "{} {} - Status: {} - IP: {} - Agent: {} - Time: {:?}",
@@ -297,42 +473,139 @@ pub async fn init(port: u16) {
     info!("Shutting down");
 
     // Cleanup
+    server.abort();
+    subscribe_task.abort();
     update_task.abort();
     history_task.abort();
-    server.abort();
+
+    IMAGE_CACHE.save_total().await;
+
+    // Write the remaining increments, they stay in the journal if this fails
+    stop_buffer.cancel();
+    let _ = buffer_task.await;
+    let mut conn = redis.clone();
+    match time::timeout(Duration::from_secs(5), buffer::flush(&mut conn)).await {
+        Ok(Ok(0)) => {}
+        Ok(Ok(written)) => info!("Wrote {} buffered increments to Redis", written),
+        _ => log::error!(
+            "Failed to write {} buffered increments to Redis before shutdown",
+            buffer::pending()
+        ),
+    }
+    let _ = tokio::task::spawn_blocking(buffer::close_journal).await;
 }
 
-async fn add(
-    name: &str,
-    count: u8,
-    agent: Option<String>,
-    mut redis: ConnectionManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    // Check for correct header
-    match agent {
-        Some(agent) if agent.contains("NekoFans") => (),
-        _ => {
-            return Ok(reply::with_status(
-                "Valid request, but unauthorized",
-                StatusCode::UNAUTHORIZED,
-            ));
+/// Recompute the total counter from the counters of every source
+pub async fn reconcile_total() {
+    init_logger();
+
+    let (_, mut redis) = connect_redis().await;
+    sources::load(&mut redis)
+        .await
+        .expect("Failed to load image sources");
+    let previous: Option<u64> = redis
+        .get(sources::TOTAL_KEY)
+        .await
+        .expect("Failed to execute redis commands");
+    let total = sources::reconcile_total(&mut redis)
+        .await
+        .expect("Failed to compute the total");
+    info!(
+        "Set the total from {} to {} images",
+        previous.unwrap_or_default(),
+        total
+    );
+}
+
+fn init_logger() {
+    if env::var_os("RUST_LOG").is_none() {
+        env::set_var("RUST_LOG", "neko_server=info");
+    }
+    if env::var_os("RUST_LOG_STYLE").is_none() {
+        env::set_var("RUST_LOG_STYLE", "never");
+    }
+    env_logger::builder()
+        .format_timestamp(None)
+        .target(env_logger::Target::Stdout)
+        .init();
+}
+
+async fn connect_redis() -> (redis::Client, ConnectionManager) {
+    let redis_url: &str = &env::var("REDIS_URL").expect("REDIS_URL must be set");
+    let redis_client = redis::Client::open(redis_url).expect("Incorrect Redis URL");
+    let redis = ConnectionManager::new(redis_client.clone())
+        .await
+        .unwrap_or_else(|_| panic!("Failed to connect to Redis at: {}", redis_url));
+    (redis_client, redis)
+}
+
+/// Wake up the update task whenever an instance changes the total, and reload the sources
+/// whenever an instance changes the registry, until the subscription is lost
+async fn watch_changes(
+    client: &redis::Client,
+    redis: &mut ConnectionManager,
+    refresh: &Notify,
+) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub
+        .subscribe(&[sources::TOTAL_CHANNEL, sources::REGISTRY_CHANNEL])
+        .await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        if message.get_channel_name() == sources::REGISTRY_CHANNEL
+            && sources::load(redis).await.is_err()
+        {
+            log::error!("Failed to load image sources from Redis");
         }
+        refresh.notify_one();
     }
+    Ok(())
+}
+
+async fn get_total_image(accept: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let format = ImageFormat::from_accept(accept.as_deref());
+    Ok(image_response(
+        IMAGE_CACHE.get_total(format).await,
+        true,
+        Some("no-cache"),
+    ))
+}
+
+/// File name of the total count image with an image extension, like `count_total.webp`
+struct TotalImageFile(ImageFormat);
 
-    // Increment the count
-    let r: Result<(), redis::RedisError> = redis.incr(name, count.to_string()).await;
+impl std::str::FromStr for TotalImageFile {
+    type Err = ();
 
-    match r {
-        Ok(_) => Ok(reply::with_status("OK", StatusCode::OK)),
-        Err(_) => Ok(reply::with_status("", StatusCode::NOT_MODIFIED)),
+    fn from_str(file: &str) -> Result<Self, Self::Err> {
+        match image_format::split_extension(file) {
+            Some(("count_total", Some(format))) => Ok(TotalImageFile(format)),
+            _ => Err(()),
+        }
     }
 }
 
-async fn get_total_image() -> Result<impl warp::Reply, warp::Rejection> {
+async fn get_total_image_file(
+    TotalImageFile(format): TotalImageFile,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(image_response(
+        IMAGE_CACHE.get_total(format).await,
+        false,
+        Some("no-cache"),
+    ))
+}
+
+async fn get_source_image(source: String) -> Result<impl warp::Reply, warp::Rejection> {
+    if !sources::is_source(&source) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(b"Unknown Source".to_vec()));
+    }
+
     Ok(Response::builder()
         .header("Content-Type", "image/png")
         .header("Cache-Control", "no-cache")
-        .body(IMAGE_CACHE.get_total().await.get_image()))
+        .body(IMAGE_CACHE.get_source(&source).await.get_image()))
 }
 
 async fn get_favicon() -> Result<impl warp::Reply, warp::Rejection> {
@@ -344,10 +617,77 @@ async fn get_favicon() -> Result<impl warp::Reply, warp::Rejection> {
         }))
 }
 
-async fn get_count_image(count: u128) -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(Response::builder()
-        .header("Content-Type", "image/png")
-        .body(IMAGE_CACHE.get_count(count).await.get_image()))
+#[derive(serde::Deserialize)]
+struct CountQuery {
+    label: Option<String>,
+    theme: Option<String>,
+}
+
+async fn get_count_image(
+    file: String,
+    accept: Option<String>,
+    query: CountQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some((count, extension)) = image_format::split_extension(&file) else {
+        return Err(warp::reject::not_found());
+    };
+    let Ok(count) = count.parse::<u128>() else {
+        return Err(warp::reject::not_found());
+    };
+    let format = extension.unwrap_or_else(|| ImageFormat::from_accept(accept.as_deref()));
+    let style = match CountStyle::new(query.label, query.theme.as_deref()) {
+        Ok(style) => style,
+        Err(message) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(message.as_bytes().to_vec())
+                .unwrap())
+        }
+    };
+
+    Ok(image_response(
+        IMAGE_CACHE.get_count(count, format, style).await,
+        extension.is_none(),
+        None,
+    ))
+}
+
+/// Build the response of a rendered image. Images in the format of the `Accept` header are `negotiated`.
+fn image_response(
+    image: Result<CountImage, RenderError>,
+    negotiated: bool,
+    cache_control: Option<&str>,
+) -> Response<Vec<u8>> {
+    let response = match image {
+        Ok(img) => {
+            let mut response =
+                Response::builder().header("Content-Type", img.format().content_type());
+            if negotiated {
+                response = response.header("Vary", "Accept");
+            }
+            if let Some(cache_control) = cache_control {
+                response = response.header("Cache-Control", cache_control);
+            }
+            response.body(img.get_image())
+        }
+        Err(RenderError::Busy) => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "1")
+            .body(b"Too many images are being rendered".to_vec()),
+        Err(RenderError::Failed) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(b"Failed to render image".to_vec()),
+    };
+    response.unwrap()
+}
+
+/// Look for the `x-forwarded-for` header, and if it's not present, fall back to x-real-ip, and if that's not present, fall back to the remote addr.
+fn client_ip(headers: &HeaderMap) -> &str {
+    headers
+        .get("x-forwarded-for")
+        .or_else(|| headers.get("x-real-ip"))
+        .map(|ip| ip.to_str().unwrap_or("Invalid Header"))
+        .unwrap_or("Unknown")
 }
 
 fn with_redis(
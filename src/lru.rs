@@ -0,0 +1,152 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Cache that removes the least recently used entries once it holds too many entries or bytes
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    // Keys by the time they were last used, oldest first
+    order: BTreeMap<u64, K>,
+    clock: u64,
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    size: usize,
+    used: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        LruCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            max_entries,
+            max_bytes,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Returns the value of a key and marks it as recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.clock += 1;
+        let key = self.order.remove(&entry.used).unwrap();
+        self.order.insert(self.clock, key);
+        entry.used = self.clock;
+        Some(&entry.value)
+    }
+
+    /// Insert a value of `size` bytes. Values larger than the whole cache are not stored.
+    pub fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        // Make room for the new value
+        while self.entries.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+                self.evictions += 1;
+            }
+        }
+
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                used: self.clock,
+            },
+        );
+        self.bytes += size;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(3, 100);
+        cache.insert(1, "one", 10);
+        cache.insert(2, "two", 10);
+        cache.insert(3, "three", 10);
+        assert_eq!(cache.get(&1), Some(&"one"));
+
+        // 2 is the least recently used entry
+        cache.insert(4, "four", 10);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
+
+        // Make room for 85 bytes by removing 3 and 4
+        cache.insert(5, "five", 85);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.get(&5), Some(&"five"));
+
+        // Too large for the whole cache
+        cache.insert(6, "six", 101);
+        assert_eq!(cache.get(&6), None);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 2,
+                bytes: 95,
+                hits: 3,
+                misses: 4,
+                evictions: 3,
+            }
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::env;
 
 use clap::Parser;
-use neko_server::init;
+use neko_server::{init, reconcile_total};
 
 #[tokio::main]
 async fn main() {
@@ -9,6 +9,9 @@ async fn main() {
     if let Some(val) = args.redis {
         env::set_var("REDIS_URL", val);
     }
+    if args.reconcile_total {
+        return reconcile_total().await;
+    }
     init(args.port).await
 }
 
@@ -22,4 +25,8 @@ struct Args {
     /// Redis URL
     #[clap(short, long, value_parser)]
     redis: Option<String>,
+
+    /// Recompute the total counter from the source counters and exit
+    #[clap(long)]
+    reconcile_total: bool,
 }
@@ -0,0 +1,182 @@
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::env;
+use std::time::Duration;
+use warp::http::HeaderMap;
+
+use crate::client_ip;
+
+const DEFAULT_REQUESTS_PER_MINUTE: u64 = 60;
+const DEFAULT_IMAGES_PER_MINUTE: u64 = 3000;
+/// How many clients with their own client id can use the full limits behind one IP
+const DEFAULT_CLIENTS_PER_IP: u64 = 10;
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+/// How long the list of throttled clients of a day is kept
+const THROTTLED_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Optional header that separates clients behind the same IP
+const CLIENT_ID_HEADER: &str = "X-Client-Id";
+
+/// Token buckets that refill `capacity` tokens per minute, with the capacity and cost of
+/// every bucket in `ARGV`. The cost is taken from every bucket or, if one of them does not
+/// have enough tokens, from none. Returns how many milliseconds to wait, 0 if allowed.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local tokens = {}
+local wait = 0
+for i, key in ipairs(KEYS) do
+    local capacity = tonumber(ARGV[2 * i - 1])
+    local cost = tonumber(ARGV[2 * i])
+    local rate = capacity / 60000
+    local bucket = redis.call('HMGET', key, 'tokens', 'time')
+    local available = tonumber(bucket[1]) or capacity
+    local last = tonumber(bucket[2]) or now
+    available = math.min(capacity, available + math.max(0, now - last) * rate)
+    if available < cost then
+        wait = math.max(wait, math.ceil((cost - available) / rate))
+    end
+    tokens[i] = available
+end
+for i, key in ipairs(KEYS) do
+    if wait == 0 then
+        tokens[i] = tokens[i] - tonumber(ARGV[2 * i])
+    end
+    redis.call('HSET', key, 'tokens', tostring(tokens[i]), 'time', now)
+    redis.call('PEXPIRE', key, 60000)
+end
+return wait
+"#;
+
+pub enum Limit {
+    Allowed,
+    /// Seconds until the request would be allowed
+    Throttled(u64),
+    /// The request costs more than a bucket holds, so it would never be allowed
+    TooLarge,
+}
+
+/// Take one request and `images` images from the buckets of the client.
+///
+/// Every request is charged to the buckets of its IP, which hold the limits of
+/// `ADD_RATE_LIMIT_CLIENTS_PER_IP` clients, and to the buckets of its client id.
+/// Requests without a client id share the client buckets of their IP.
+pub async fn check(redis: &mut ConnectionManager, headers: &HeaderMap, images: u64) -> Limit {
+    let (ip, client) = client_key(headers);
+    let clients_per_ip = limit("ADD_RATE_LIMIT_CLIENTS_PER_IP", DEFAULT_CLIENTS_PER_IP).max(1);
+    let limits = [
+        (
+            "requests",
+            limit("ADD_RATE_LIMIT_PER_MINUTE", DEFAULT_REQUESTS_PER_MINUTE),
+            1,
+        ),
+        (
+            "images",
+            limit(
+                "ADD_RATE_LIMIT_IMAGES_PER_MINUTE",
+                DEFAULT_IMAGES_PER_MINUTE,
+            ),
+            images,
+        ),
+    ];
+
+    let script = redis::Script::new(TOKEN_BUCKET_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    let mut buckets = 0;
+    for (bucket, capacity, cost) in limits {
+        if capacity == 0 {
+            continue;
+        }
+        if cost > capacity {
+            log::warn!(
+                "Rejected add request of {} {} from {}",
+                cost,
+                bucket,
+                client
+            );
+            return Limit::TooLarge;
+        }
+        invocation
+            .key(format!("ratelimit:{}:ip:{}", bucket, ip))
+            .arg(capacity.saturating_mul(clients_per_ip))
+            .arg(cost)
+            .key(format!("ratelimit:{}:client:{}", bucket, client))
+            .arg(capacity)
+            .arg(cost);
+        buckets += 1;
+    }
+    if buckets == 0 {
+        return Limit::Allowed;
+    }
+
+    let wait_ms: u64 = match invocation.invoke_async(redis).await {
+        Ok(wait) => wait,
+        Err(error) => {
+            // Rather count too much than lose images while Redis has problems
+            log::error!("Failed to check rate limit in Redis: {}", error);
+            return Limit::Allowed;
+        }
+    };
+
+    if wait_ms == 0 {
+        return Limit::Allowed;
+    }
+
+    log::warn!("Throttled add request from {}", client);
+    record_throttled(redis, &client).await;
+    Limit::Throttled(wait_ms.div_ceil(1000))
+}
+
+/// Count throttled requests per client and day, so abuse can be looked up later
+async fn record_throttled(redis: &mut ConnectionManager, client: &str) {
+    let key = throttled_key(chrono::Utc::now().date_naive());
+    let mut pipe = redis::pipe();
+    pipe.zincr(&key, client, 1).ignore();
+    pipe.expire(&key, THROTTLED_RETENTION.as_secs() as i64)
+        .ignore();
+    if let Err(error) = pipe.query_async::<()>(redis).await {
+        log::error!("Failed to record throttled request in Redis: {}", error);
+    }
+}
+
+/// Returns the clients with the most throttled requests on a day
+pub async fn throttled(
+    redis: &mut ConnectionManager,
+    date: chrono::NaiveDate,
+    limit: isize,
+) -> Result<Vec<(String, u64)>, redis::RedisError> {
+    redis
+        .zrevrange_withscores(throttled_key(date), 0, limit - 1)
+        .await
+}
+
+fn throttled_key(date: chrono::NaiveDate) -> String {
+    format!("ratelimit:throttled:{}", date.format("%Y%m%d"))
+}
+
+/// The IP of the client, and the IP followed by the client id if one was sent
+fn client_key(headers: &HeaderMap) -> (String, String) {
+    let ip = client_ip(headers);
+    let client_id = headers
+        .get(CLIENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_CLIENT_ID_LENGTH
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        });
+
+    match client_id {
+        Some(client_id) => (ip.to_string(), format!("{}/{}", ip, client_id)),
+        None => (ip.to_string(), ip.to_string()),
+    }
+}
+
+fn limit(variable: &str, default: u64) -> u64 {
+    env::var(variable)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(default)
+}
@@ -2,11 +2,16 @@ use chrono::prelude::*;
 use log::{debug, error};
 
 use crate::const_image;
+use crate::theme;
 
 struct SeasonalImage {
     name: &'static str,
     condition: fn(&DateTime<Utc>) -> bool,
     image: fn(&DateTime<Utc>) -> image::RgbaImage,
+    /// The image is different on every day of the season
+    daily: bool,
+    /// Digit theme of the season
+    theme: &'static str,
 }
 
 static TOTAL_IMAGES: [SeasonalImage; 4] = [
@@ -14,21 +19,29 @@ static TOTAL_IMAGES: [SeasonalImage; 4] = [
         name: "Halloween",
         condition: is_halloween,
         image: |_| const_image::HEADER_HALLOWEEN.clone(),
+        daily: false,
+        theme: "halloween",
     },
     SeasonalImage {
         name: "Christmas Advent",
         condition: |date| date.month() == 12 && date.day() <= 22,
         image: |date| const_image::HEADER_CHRISTMAS_DAYS[(date.day() - 1) as usize].clone(),
+        daily: true,
+        theme: "christmas",
     },
     SeasonalImage {
         name: "Christmas Holliday",
         condition: |date| date.month() == 12 && date.day() > 22 && date.day() < 28,
         image: |_| const_image::HEADER_CHRISTMAS.clone(),
+        daily: false,
+        theme: "christmas",
     },
     SeasonalImage {
         name: "Default",
         condition: |_| true,
         image: |_| const_image::HEADER.clone(),
+        daily: false,
+        theme: theme::DEFAULT,
     },
 ];
 
@@ -54,6 +67,60 @@ pub fn seasonal_name() -> &'static str {
     "Default"
 }
 
+/// Returns the name of the digit theme of the current season
+pub fn seasonal_theme() -> &'static str {
+    let date = Utc::now();
+    TOTAL_IMAGES
+        .iter()
+        .find(|img| (img.condition)(&date))
+        .map_or(theme::DEFAULT, |img| img.theme)
+}
+
+/// Returns a key that changes whenever the seasonal image changes
+pub fn seasonal_key(date: &DateTime<Utc>) -> String {
+    match TOTAL_IMAGES.iter().find(|img| (img.condition)(date)) {
+        Some(img) if img.daily => format!("{} {}", img.name, date.ordinal()),
+        Some(img) => img.name.to_string(),
+        None => "Default".to_string(),
+    }
+}
+
+/// Returns the next midnight at which the seasonal image changes
+pub fn next_change(date: &DateTime<Utc>) -> DateTime<Utc> {
+    let key = seasonal_key(date);
+    let mut midnight = date.date_naive().and_time(NaiveTime::MIN).and_utc();
+    for _ in 0..366 {
+        midnight += chrono::Duration::days(1);
+        if seasonal_key(&midnight) != key {
+            break;
+        }
+    }
+    midnight
+}
+
 fn is_halloween(date: &DateTime<Utc>) -> bool {
     (date.month() == 10 && date.day() >= 19) || (date.month() == 11 && date.day() <= 3)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, month, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn changes_at_season_boundaries() {
+        assert_eq!(next_change(&date(6, 1, 12)), date(10, 19, 0));
+        assert_eq!(next_change(&date(10, 19, 0)), date(11, 4, 0));
+        assert_eq!(next_change(&date(12, 5, 23)), date(12, 6, 0));
+        assert_eq!(next_change(&date(12, 22, 1)), date(12, 23, 0));
+        assert_eq!(next_change(&date(12, 23, 1)), date(12, 28, 0));
+        assert_ne!(seasonal_key(&date(12, 5, 0)), seasonal_key(&date(12, 6, 0)));
+        assert_eq!(
+            seasonal_key(&date(10, 20, 0)),
+            seasonal_key(&date(11, 2, 0))
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::history;
+
+/// Sources that are registered when the registry in Redis is empty, and if they are deprecated
+const DEFAULT_SOURCES: [(&str, bool); 15] = [
+    ("nekos.life", false),
+    ("nekos.best", false),
+    ("nekosapi.com", false),
+    ("nekosia.cat", false),
+    ("pic.re", false),
+    ("purrbot", false),
+    ("shibe.online", false),
+    ("catboys", false),
+    ("waifu.im", false),
+    ("waifu.pics", false),
+    ("dog_ceo", false),
+    ("the_cat_api", false),
+    ("twitter_search", true),
+    ("twitter_user_timeline", true),
+    ("testing", true),
+];
+
+/// Redis hash with the settings of every source, keyed by the source name
+const REGISTRY_KEY: &str = "sources:registry";
+const MAX_NAME_LENGTH: usize = 64;
+/// Counter with the sum of every source, updated together with the source counters
+pub const TOTAL_KEY: &str = "total";
+/// Channel that gets a message with the number of added images whenever the total changes
+pub const TOTAL_CHANNEL: &str = "counter:total";
+/// Channel that gets a message with the name of a source whenever the registry changes
+pub const REGISTRY_CHANNEL: &str = "sources:changed";
+/// Names of other keys that a counter must not overwrite
+const RESERVED_NAMES: [&str; 2] = ["auth_test", TOTAL_KEY];
+
+/// Lua functions that look up names in the registry, prepended to the scripts that use them
+const REGISTRY_FUNCTIONS: &str = r#"
+local function aliases(settings)
+    return cjson.decode(settings).aliases or {}
+end
+
+-- Returns the source that a name or an alias belongs to, or false
+local function owner(registry, name)
+    if redis.call('HEXISTS', registry, name) == 1 then return name end
+    local entries = redis.call('HGETALL', registry)
+    for i = 1, #entries, 2 do
+        for _, alias in ipairs(aliases(entries[i + 1])) do
+            if alias == name then return entries[i] end
+        end
+    end
+    return false
+end
+"#;
+
+/// Registers a source if its name is not used by another source or alias
+const ADD_SCRIPT: &str = r#"
+local used = owner(KEYS[1], ARGV[1])
+if used == ARGV[1] then return 0 end
+if used then return -1 end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+redis.call('PUBLISH', ARGV[3], ARGV[1])
+return 1
+"#;
+
+/// Replaces the settings of a source if none of its aliases belong to another source
+const UPDATE_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then return 0 end
+for _, alias in ipairs(aliases(ARGV[2])) do
+    local used = owner(KEYS[1], alias)
+    if used and used ~= ARGV[1] then return -1 end
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+redis.call('PUBLISH', ARGV[3], ARGV[1])
+return 1
+"#;
+
+/// Renames the registry entry, the counter and the history of a source in one step
+const RENAME_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then return 0 end
+if redis.call('HEXISTS', KEYS[1], ARGV[2]) == 1 then return -1 end
+local used = owner(KEYS[1], ARGV[2])
+if used and used ~= ARGV[1] then return -2 end
+for i = 2, #KEYS, 2 do
+    if redis.call('EXISTS', KEYS[i + 1]) == 1 then return -1 end
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('HSET', KEYS[1], ARGV[2], ARGV[3])
+for i = 2, #KEYS, 2 do
+    if redis.call('EXISTS', KEYS[i]) == 1 then redis.call('RENAME', KEYS[i], KEYS[i + 1]) end
+end
+redis.call('PUBLISH', ARGV[4], ARGV[2])
+return 1
+"#;
+
+/// Adds the counter and the history of one source to another and removes the first source
+/// from the registry. History points at the same time are summed.
+const MERGE_SCRIPT: &str = r#"
+if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then return -1 end
+if redis.call('HEXISTS', KEYS[1], ARGV[2]) == 0 then return -1 end
+local count = tonumber(redis.call('GET', KEYS[2]) or '0')
+redis.call('INCRBY', KEYS[3], count)
+redis.call('DEL', KEYS[2])
+for i = 4, #KEYS, 2 do
+    local points = redis.call('ZRANGE', KEYS[i], 0, -1, 'WITHSCORES')
+    for j = 1, #points, 2 do
+        local time = tonumber(points[j + 1])
+        local value = tonumber(string.match(points[j], ':(%d+)$'))
+        local existing = redis.call('ZRANGEBYSCORE', KEYS[i + 1], time, time)
+        if existing[1] then
+            value = value + tonumber(string.match(existing[1], ':(%d+)$'))
+        end
+        redis.call('ZREMRANGEBYSCORE', KEYS[i + 1], time, time)
+        redis.call('ZADD', KEYS[i + 1], time, string.format('%d:%d', time, value))
+    end
+    redis.call('DEL', KEYS[i])
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+redis.call('HSET', KEYS[1], ARGV[2], ARGV[3])
+redis.call('PUBLISH', ARGV[4], ARGV[1])
+return count
+"#;
+
+/// Adds images to the counters of sources and to the total. Names are resolved against the
+/// registry, so counts for a source that was merged or renamed meanwhile go to the source that
+/// has its old name as an alias. Returns the number of images of names that are neither a source
+/// nor an alias, which are not counted.
+const INCREMENT_SCRIPT: &str = r#"
+local total = 0
+local dropped = 0
+for i = 3, #KEYS do
+    local source = owner(KEYS[1], KEYS[i])
+    if source then
+        redis.call('INCRBY', source, ARGV[i - 1])
+        total = total + tonumber(ARGV[i - 1])
+    else
+        dropped = dropped + tonumber(ARGV[i - 1])
+    end
+end
+redis.call('INCRBY', KEYS[2], string.format('%d', total))
+redis.call('PUBLISH', ARGV[1], string.format('%d', total))
+return dropped
+"#;
+
+/// Sets the total to the sum of the source counters
+const RECONCILE_SCRIPT: &str = r#"
+local total = 0
+for i = 2, #KEYS do
+    total = total + tonumber(redis.call('GET', KEYS[i]) or '0')
+end
+redis.call('SET', KEYS[1], string.format('%d', total))
+return total
+"#;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Source {
+    #[serde(skip_deserializing)]
+    pub name: String,
+    pub enabled: bool,
+    /// Deprecated sources accept new counts but are hidden from per-source listings
+    #[serde(default)]
+    pub deprecated: bool,
+    /// Other names that count towards this source
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct SourceChange {
+    pub enabled: Option<bool>,
+    pub deprecated: Option<bool>,
+    pub aliases: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    InvalidName,
+    NotFound,
+    AlreadyExists,
+    AliasInUse,
+    Redis(redis::RedisError),
+}
+
+impl From<redis::RedisError> for RegistryError {
+    fn from(error: redis::RedisError) -> Self {
+        RegistryError::Redis(error)
+    }
+}
+
+lazy_static::lazy_static! {
+    // Local copy of the registry, reloaded from Redis by the update task
+    static ref REGISTRY: RwLock<Vec<Source>> = RwLock::new(Vec::new());
+}
+
+/// Returns every registered source
+pub fn all() -> Vec<Source> {
+    REGISTRY.read().unwrap().clone()
+}
+
+/// Returns the name of every registered source
+pub fn names() -> Vec<String> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .map(|source| source.name.clone())
+        .collect()
+}
+
+/// Returns true if `name` is a registered image source
+pub fn is_source(name: &str) -> bool {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .any(|source| source.name == name)
+}
+
+/// Returns true if `name` is a registered image source that is not deprecated
+pub fn is_listed(name: &str) -> bool {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .any(|source| source.name == name && !source.deprecated)
+}
+
+/// Returns the name of every source that is not deprecated
+pub fn listed_names() -> Vec<String> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|source| !source.deprecated)
+        .map(|source| source.name.clone())
+        .collect()
+}
+
+/// Returns the counter name of a source or alias that accepts new counts
+pub fn resolve(name: &str) -> Option<String> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .find(|source| source.name == name || source.aliases.iter().any(|alias| alias == name))
+        .filter(|source| source.enabled)
+        .map(|source| source.name.clone())
+}
+
+/// Reload the registry from Redis. Registers the default sources if it is empty.
+pub async fn load(redis: &mut ConnectionManager) -> Result<(), redis::RedisError> {
+    let mut entries: HashMap<String, String> = redis.hgetall(REGISTRY_KEY).await?;
+    if entries.is_empty() {
+        log::info!(
+            "Registering {} default image sources",
+            DEFAULT_SOURCES.len()
+        );
+        let mut pipe = redis::pipe();
+        for (name, deprecated) in DEFAULT_SOURCES {
+            let source = Source {
+                deprecated,
+                ..new_source(name)
+            };
+            pipe.hset_nx(REGISTRY_KEY, name, settings(&source)).ignore();
+        }
+        pipe.query_async::<()>(redis).await?;
+        entries = redis.hgetall(REGISTRY_KEY).await?;
+    }
+
+    let mut sources: Vec<Source> = entries
+        .into_iter()
+        .filter_map(
+            |(name, value)| match serde_json::from_str::<Source>(&value) {
+                Ok(source) => Some(Source { name, ..source }),
+                Err(_) => {
+                    log::warn!("Ignoring invalid settings of image source {}", name);
+                    None
+                }
+            },
+        )
+        .collect();
+    sources.sort_by(|a, b| a.name.cmp(&b.name));
+
+    *REGISTRY.write().unwrap() = sources;
+    Ok(())
+}
+
+/// Register a new source
+pub async fn add(redis: &mut ConnectionManager, name: &str) -> Result<Source, RegistryError> {
+    let name = normalize_name(name)?;
+    let source = new_source(&name);
+    let added: i64 = registry_script(ADD_SCRIPT)
+        .key(REGISTRY_KEY)
+        .arg(&name)
+        .arg(settings(&source))
+        .arg(REGISTRY_CHANNEL)
+        .invoke_async(redis)
+        .await?;
+    match added {
+        0 => return Err(RegistryError::AlreadyExists),
+        -1 => return Err(RegistryError::AliasInUse),
+        _ => {}
+    }
+    load(redis).await?;
+    Ok(source)
+}
+
+/// Change the settings of a source. Disabled sources are still counted but reject new counts.
+pub async fn update(
+    redis: &mut ConnectionManager,
+    name: &str,
+    change: SourceChange,
+) -> Result<Source, RegistryError> {
+    let mut source = get(redis, name).await?;
+    if let Some(enabled) = change.enabled {
+        source.enabled = enabled;
+    }
+    if let Some(deprecated) = change.deprecated {
+        source.deprecated = deprecated;
+    }
+    if let Some(aliases) = change.aliases {
+        let mut normalized = Vec::with_capacity(aliases.len());
+        for alias in aliases {
+            let alias = normalize_name(&alias)?;
+            if alias == name {
+                return Err(RegistryError::AliasInUse);
+            }
+            normalized.push(alias);
+        }
+        normalized.sort();
+        normalized.dedup();
+        source.aliases = normalized;
+    }
+
+    let updated: i64 = registry_script(UPDATE_SCRIPT)
+        .key(REGISTRY_KEY)
+        .arg(name)
+        .arg(settings(&source))
+        .arg(REGISTRY_CHANNEL)
+        .invoke_async(redis)
+        .await?;
+    match updated {
+        0 => return Err(RegistryError::NotFound),
+        -1 => return Err(RegistryError::AliasInUse),
+        _ => {}
+    }
+    load(redis).await?;
+    Ok(source)
+}
+
+/// Rename a source together with its counter and history. The old name becomes an alias.
+pub async fn rename(
+    redis: &mut ConnectionManager,
+    name: &str,
+    new_name: &str,
+) -> Result<Source, RegistryError> {
+    let new_name = normalize_name(new_name)?;
+    let mut source = get(redis, name).await?;
+    source.name = new_name.clone();
+    // Keep accepting counts that are sent or buffered under the old name
+    source.aliases.retain(|alias| *alias != new_name);
+    source.aliases.push(name.to_string());
+    source.aliases.sort();
+    source.aliases.dedup();
+
+    let result: i64 = registry_script(RENAME_SCRIPT)
+        .key(REGISTRY_KEY)
+        .key(name)
+        .key(&new_name)
+        .key(history::hourly_key(name))
+        .key(history::hourly_key(&new_name))
+        .key(history::daily_key(name))
+        .key(history::daily_key(&new_name))
+        .arg(name)
+        .arg(&new_name)
+        .arg(settings(&source))
+        .arg(REGISTRY_CHANNEL)
+        .invoke_async(redis)
+        .await?;
+    match result {
+        0 => return Err(RegistryError::NotFound),
+        -1 => return Err(RegistryError::AlreadyExists),
+        -2 => return Err(RegistryError::AliasInUse),
+        _ => {}
+    }
+
+    load(redis).await?;
+    Ok(source)
+}
+
+/// Fold the counter of `name` into `into`. The merged source and its aliases become aliases of `into`.
+/// Returns the updated source and the number of images that were moved.
+pub async fn merge(
+    redis: &mut ConnectionManager,
+    name: &str,
+    into: &str,
+) -> Result<(Source, u64), RegistryError> {
+    if name == into {
+        return Err(RegistryError::InvalidName);
+    }
+    let merged = get(redis, name).await?;
+    let mut source = get(redis, into).await?;
+    source.aliases.push(merged.name);
+    source.aliases.extend(merged.aliases);
+    source.aliases.sort();
+    source.aliases.dedup();
+
+    let moved: i64 = redis::Script::new(MERGE_SCRIPT)
+        .key(REGISTRY_KEY)
+        .key(name)
+        .key(into)
+        .key(history::hourly_key(name))
+        .key(history::hourly_key(into))
+        .key(history::daily_key(name))
+        .key(history::daily_key(into))
+        .arg(name)
+        .arg(into)
+        .arg(settings(&source))
+        .arg(REGISTRY_CHANNEL)
+        .invoke_async(redis)
+        .await?;
+    if moved < 0 {
+        return Err(RegistryError::NotFound);
+    }
+
+    load(redis).await?;
+    Ok((source, moved as u64))
+}
+
+/// Get the total and the counter of every registered source in one atomic pipeline
+pub async fn counts(
+    redis: &mut ConnectionManager,
+) -> Result<(u64, Vec<(String, u64)>), redis::RedisError> {
+    let names = names();
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.get(TOTAL_KEY);
+    for source in names.iter() {
+        pipe.get(source);
+    }
+    let mut results = pipe
+        .query_async::<Vec<Option<u64>>>(redis)
+        .await?
+        .into_iter()
+        .map(Option::unwrap_or_default);
+    let total = results.next().unwrap_or_default();
+
+    Ok((total, names.into_iter().zip(results).collect()))
+}
+
+/// Add images to the counters of sources and to the total in one transaction
+pub async fn increment<'a>(
+    redis: &mut ConnectionManager,
+    increments: impl IntoIterator<Item = (&'a str, u64)>,
+) -> Result<(), redis::RedisError> {
+    let script = registry_script(INCREMENT_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(REGISTRY_KEY)
+        .key(TOTAL_KEY)
+        .arg(TOTAL_CHANNEL);
+    for (source, count) in increments {
+        invocation.key(source).arg(count);
+    }
+    let dropped: u64 = invocation.invoke_async(redis).await?;
+    if dropped > 0 {
+        log::warn!(
+            "Dropped {} images of sources that are no longer registered",
+            dropped
+        );
+    }
+    Ok(())
+}
+
+/// Recompute the total from the counters of every registered source. Returns the new total.
+pub async fn reconcile_total(redis: &mut ConnectionManager) -> Result<u64, redis::RedisError> {
+    let script = redis::Script::new(RECONCILE_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation.key(TOTAL_KEY);
+    for source in names() {
+        invocation.key(source);
+    }
+    invocation.invoke_async(redis).await
+}
+
+/// Read a single source from Redis
+async fn get(redis: &mut ConnectionManager, name: &str) -> Result<Source, RegistryError> {
+    let value: Option<String> = redis.hget(REGISTRY_KEY, name).await?;
+    let source = value
+        .and_then(|value| serde_json::from_str::<Source>(&value).ok())
+        .ok_or(RegistryError::NotFound)?;
+    Ok(Source {
+        name: name.to_string(),
+        ..source
+    })
+}
+
+/// Build a script that uses the registry functions
+fn registry_script(code: &str) -> redis::Script {
+    redis::Script::new(&format!("{}{}", REGISTRY_FUNCTIONS, code))
+}
+
+fn new_source(name: &str) -> Source {
+    Source {
+        name: name.to_string(),
+        enabled: true,
+        deprecated: false,
+        aliases: Vec::new(),
+    }
+}
+
+fn settings(source: &Source) -> String {
+    serde_json::to_string(source).unwrap()
+}
+
+fn normalize_name(name: &str) -> Result<String, RegistryError> {
+    let name = name.trim().to_ascii_lowercase();
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || RESERVED_NAMES.contains(&name.as_str())
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(RegistryError::InvalidName);
+    }
+    Ok(name)
+}
@@ -0,0 +1,58 @@
+use redis::aio::ConnectionManager;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use warp::hyper::StatusCode;
+
+use crate::json::{json_error, json_response};
+use crate::lru::CacheStats;
+use crate::{buffer, season_images, sources, IMAGE_CACHE};
+
+#[derive(Serialize)]
+struct StatsResponse {
+    sources: BTreeMap<String, u64>,
+    total: u64,
+    last_update: Option<String>,
+    season: &'static str,
+    buffer: BufferStats,
+    count_cache: CacheStats,
+}
+
+#[derive(Serialize)]
+struct BufferStats {
+    pending: u64,
+    dropped: u64,
+}
+
+/// Returns the current value of every counter as JSON
+pub async fn stats(mut redis: ConnectionManager) -> Result<impl warp::Reply, warp::Rejection> {
+    let (total, counts) = match sources::counts(&mut redis).await {
+        Ok(counts) => counts,
+        Err(_) => {
+            log::error!("Failed to get image count from Redis");
+            return Ok(json_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database unavailable",
+            ));
+        }
+    };
+
+    let response = StatsResponse {
+        sources: counts
+            .into_iter()
+            .filter(|(source, _)| sources::is_listed(source))
+            .collect(),
+        total,
+        last_update: IMAGE_CACHE
+            .last_update()
+            .await
+            .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        season: season_images::seasonal_name(),
+        buffer: BufferStats {
+            pending: buffer::pending(),
+            dropped: buffer::dropped(),
+        },
+        count_cache: IMAGE_CACHE.count_cache_stats().await,
+    };
+
+    Ok(json_response(StatusCode::OK, &response, None))
+}
@@ -0,0 +1,129 @@
+use ab_glyph::{point, Font, FontRef, Glyph, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+
+lazy_static::lazy_static! {
+    static ref FONT: FontRef<'static> = FontRef::try_from_slice(include_bytes!("../fonts/Cute Notes.ttf"))
+        .expect("Could not load font: Cute Notes.ttf");
+}
+
+/// Color and size of rendered text
+#[derive(Debug, Clone, Copy)]
+pub struct TextStyle {
+    /// Height of the font in pixels
+    pub size: f32,
+    pub color: Rgba<u8>,
+    /// Color and width in pixels of a border around every glyph
+    pub outline: Option<(Rgba<u8>, u32)>,
+}
+
+/// Place the glyphs of a line of text, with kerning. Returns the glyphs and the width of the line.
+fn layout(text: &str, size: f32) -> (Vec<Glyph>, f32) {
+    let scale = PxScale::from(size);
+    let font = FONT.as_scaled(scale);
+
+    let mut glyphs = Vec::new();
+    let mut caret = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(scale, point(caret, font.ascent())));
+        caret += font.h_advance(id);
+        previous = Some(id);
+    }
+    (glyphs, caret)
+}
+
+/// Returns the width and height in pixels of a line of text, without the outline
+pub fn measure(text: &str, size: f32) -> (u32, u32) {
+    let font = FONT.as_scaled(PxScale::from(size));
+    let (_, width) = layout(text, size);
+    (width.ceil() as u32, font.height().ceil() as u32)
+}
+
+/// Render a line of text on a transparent image that fits the text and its outline
+pub fn render(text: &str, style: &TextStyle) -> RgbaImage {
+    let (width, height) = measure(text, style.size);
+    let border = style.outline.map_or(0, |(_, border)| border);
+    let mut img = RgbaImage::new(width + 2 * border, height + 2 * border);
+
+    // Draw the outline by drawing the text in the outline color around its position
+    if let Some((color, border)) = style.outline {
+        let border = border as i32;
+        for y in -border..=border {
+            for x in -border..=border {
+                if x * x + y * y <= border * border {
+                    draw(&mut img, text, style.size, color, (border + x, border + y));
+                }
+            }
+        }
+    }
+    draw(
+        &mut img,
+        text,
+        style.size,
+        style.color,
+        (border as i32, border as i32),
+    );
+    img
+}
+
+/// Blend the glyphs of a line of text onto an image, with the top left corner of the line at `offset`
+fn draw(img: &mut RgbaImage, text: &str, size: f32, color: Rgba<u8>, offset: (i32, i32)) {
+    let (glyphs, _) = layout(text, size);
+    for glyph in glyphs {
+        let Some(outlined) = FONT.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, coverage| {
+            let x = bounds.min.x as i32 + x as i32 + offset.0;
+            let y = bounds.min.y as i32 + y as i32 + offset.1;
+            if x < 0 || y < 0 || x >= img.width() as i32 || y >= img.height() as i32 {
+                return;
+            }
+            blend(img.get_pixel_mut(x as u32, y as u32), color, coverage);
+        });
+    }
+}
+
+/// Draw `color` over a pixel with the opacity of `coverage`
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>, coverage: f32) {
+    let alpha = coverage.clamp(0.0, 1.0) * color[3] as f32 / 255.0;
+    let below = pixel[3] as f32 / 255.0;
+    let out = alpha + below * (1.0 - alpha);
+    if out <= 0.0 {
+        return;
+    }
+    for i in 0..3 {
+        let value = (color[i] as f32 * alpha + pixel[i] as f32 * below * (1.0 - alpha)) / out;
+        pixel[i] = value.round() as u8;
+    }
+    pixel[3] = (out * 255.0).round() as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_text_with_outline() {
+        let style = TextStyle {
+            size: 40.0,
+            color: Rgba([0, 0, 0, 255]),
+            outline: Some((Rgba([255, 255, 255, 255]), 2)),
+        };
+        let (width, height) = measure("images shown", style.size);
+        assert!(width > measure("images", style.size).0);
+
+        let img = render("images shown", &style);
+        assert_eq!(img.dimensions(), (width + 4, height + 4));
+        assert!(img.pixels().any(|pixel| *pixel == Rgba([0, 0, 0, 255])));
+        assert!(img
+            .pixels()
+            .any(|pixel| *pixel == Rgba([255, 255, 255, 255])));
+        assert_eq!(measure("", style.size).0, 0);
+    }
+}
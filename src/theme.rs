@@ -0,0 +1,308 @@
+use base64::prelude::*;
+use image::Rgba;
+use log::{error, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{env, fs};
+
+use crate::const_image;
+use crate::text::{self, TextStyle};
+
+/// Name of the theme of the digits in `template/numbers`
+pub const DEFAULT: &str = "default";
+/// Name of the theme with digits drawn from the font
+pub const FONT: &str = "font";
+/// Pink digits with a white border, about as high as the default digits
+const FONT_STYLE: TextStyle = TextStyle {
+    size: 150.0,
+    color: Rgba([236, 97, 146, 255]),
+    outline: Some((Rgba([255, 255, 255, 255]), 4)),
+};
+
+/// Longest name of a theme that is loaded from the themes directory
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Sprite atlases and their descriptors by theme name, used unless the themes directory has
+/// a theme with the same name
+const ATLASES: [(&str, &[u8], &str); 3] = [
+    (
+        "pixel",
+        include_bytes!("../template/themes/pixel.png"),
+        include_str!("../template/themes/pixel.json"),
+    ),
+    (
+        "halloween",
+        include_bytes!("../template/themes/halloween.png"),
+        include_str!("../template/themes/halloween.json"),
+    ),
+    (
+        "christmas",
+        include_bytes!("../template/themes/christmas.png"),
+        include_str!("../template/themes/christmas.json"),
+    ),
+];
+
+lazy_static::lazy_static! {
+    static ref THEMES: HashMap<&'static str, Theme> = {
+        let mut themes: HashMap<_, _> = ATLASES
+            .iter()
+            .map(|(name, atlas, descriptor)| (*name, Theme::from_atlas(name, atlas, descriptor)))
+            .collect();
+        themes.insert(DEFAULT, Theme::new(DEFAULT, const_image::NUMBERS.clone()));
+        themes.insert(FONT, Theme::from_font(FONT, &FONT_STYLE));
+        if let Some(dir) = themes_dir() {
+            for theme in load_directory(Path::new(&dir)) {
+                if matches!(theme.name, DEFAULT | FONT) {
+                    warn!("Ignoring theme {} in {}, the name is reserved", theme.name, dir);
+                    continue;
+                }
+                themes.insert(theme.name, theme);
+            }
+        }
+        themes
+    };
+}
+
+/// Position of a glyph in a sprite atlas
+#[derive(Deserialize)]
+struct GlyphBox {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Descriptor of a sprite atlas, with the box of every digit by the digit
+#[derive(Deserialize)]
+struct Descriptor {
+    glyphs: HashMap<String, GlyphBox>,
+}
+
+/// A set of digit sprites
+pub struct Theme {
+    name: &'static str,
+    digits: [image::RgbaImage; 10],
+    // Digits as base64 encoded PNG for SVG images
+    sprites: [String; 10],
+}
+
+impl Theme {
+    fn new(name: &'static str, digits: [image::RgbaImage; 10]) -> Self {
+        let sprites = digits.clone().map(|digit| {
+            let mut buffer = std::io::Cursor::new(Vec::new());
+            digit
+                .write_to(&mut buffer, image::ImageFormat::Png)
+                .unwrap();
+            BASE64_STANDARD.encode(buffer.into_inner())
+        });
+        Theme {
+            name,
+            digits,
+            sprites,
+        }
+    }
+
+    /// Cut the digits out of a sprite atlas. Digits that are missing from the atlas use the default digits.
+    fn from_atlas(name: &'static str, atlas: &[u8], descriptor: &str) -> Self {
+        let mut digits = const_image::NUMBERS.clone();
+        let atlas = match image::load_from_memory(atlas) {
+            Ok(atlas) => atlas.to_rgba8(),
+            Err(e) => {
+                error!("Could not load the atlas of theme {}: {}", name, e);
+                return Theme::new(name, digits);
+            }
+        };
+        let descriptor: Descriptor = match serde_json::from_str(descriptor) {
+            Ok(descriptor) => descriptor,
+            Err(e) => {
+                error!("Could not parse the descriptor of theme {}: {}", name, e);
+                return Theme::new(name, digits);
+            }
+        };
+
+        for (i, digit) in digits.iter_mut().enumerate() {
+            let Some(glyph) = descriptor.glyphs.get(&i.to_string()) else {
+                warn!("Theme {} has no digit {}, using the default", name, i);
+                continue;
+            };
+            if glyph.width == 0
+                || glyph.height == 0
+                || glyph.x + glyph.width > atlas.width()
+                || glyph.y + glyph.height > atlas.height()
+            {
+                warn!("Digit {} of theme {} is outside of the atlas", i, name);
+                continue;
+            }
+            *digit = image::imageops::crop_imm(&atlas, glyph.x, glyph.y, glyph.width, glyph.height)
+                .to_image();
+        }
+        Theme::new(name, digits)
+    }
+
+    /// Draw the digits with the font of the labels
+    fn from_font(name: &'static str, style: &TextStyle) -> Self {
+        let digits = std::array::from_fn(|digit| text::render(&digit.to_string(), style));
+        Theme::new(name, digits)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn digit(&self, digit: usize) -> &image::RgbaImage {
+        &self.digits[digit]
+    }
+
+    /// Returns the digit as base64 encoded PNG
+    pub fn sprite(&self, digit: usize) -> &str {
+        &self.sprites[digit]
+    }
+
+    /// Returns the size of a number drawn with this theme. Digits are placed next to each other
+    /// and centered vertically.
+    pub fn number_size(&self, number: &str) -> (u32, u32) {
+        number
+            .chars()
+            .filter_map(|digit| digit.to_digit(10))
+            .map(|digit| self.digits[digit as usize].dimensions())
+            .fold((0, 0), |(width, height), digit| {
+                (width + digit.0, height.max(digit.1))
+            })
+    }
+}
+
+/// Returns the name of every theme, sorted
+pub fn names() -> Vec<&'static str> {
+    let mut names: Vec<_> = THEMES.keys().copied().collect();
+    names.sort();
+    names
+}
+
+/// Returns the theme with that name
+pub fn get(name: &str) -> Option<&'static Theme> {
+    THEMES.get(name)
+}
+
+/// Returns the theme of the current season, or the default theme
+pub fn seasonal() -> &'static Theme {
+    get(crate::season_images::seasonal_theme()).unwrap_or_else(|| &THEMES[DEFAULT])
+}
+
+/// Load every sprite atlas `<name>.png` that has a descriptor `<name>.json` in a directory
+fn load_directory(dir: &Path) -> Vec<Theme> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Could not read the themes in {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut themes = Vec::new();
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("png") {
+            continue;
+        }
+        let Some(name) = path
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .filter(|name| is_valid_name(name))
+        else {
+            warn!("Ignoring theme {} with an invalid name", path.display());
+            continue;
+        };
+        let (atlas, descriptor) = match (
+            fs::read(&path),
+            fs::read_to_string(path.with_extension("json")),
+        ) {
+            (Ok(atlas), Ok(descriptor)) => (atlas, descriptor),
+            _ => {
+                warn!(
+                    "Ignoring theme {}, it has no readable atlas and descriptor",
+                    name
+                );
+                continue;
+            }
+        };
+        // Themes are loaded once and kept until the server stops
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        themes.push(Theme::from_atlas(name, &atlas, &descriptor));
+    }
+    themes
+}
+
+/// Names are part of the render cache keys, so only allow a few characters
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+}
+
+fn themes_dir() -> Option<String> {
+    env::var("THEMES_DIR").ok().filter(|dir| !dir.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_themes_from_atlases() {
+        let default = get(DEFAULT).unwrap();
+        assert_eq!(default.number_size("123"), (3 * 68, 150));
+
+        let pixel = get("pixel").unwrap();
+        assert_eq!(pixel.number_size("42"), (2 * 66, 100));
+        assert!(get("unknown").is_none());
+
+        // Missing glyphs and glyphs outside of the atlas use the default digits
+        let theme = Theme::from_atlas(
+            "partial",
+            include_bytes!("../template/themes/pixel.png"),
+            r#"{"glyphs": {"1": {"x": 70, "y": 0, "width": 66, "height": 100},
+                           "2": {"x": 340, "y": 0, "width": 66, "height": 100}}}"#,
+        );
+        assert_eq!(theme.digit(1), pixel.digit(1));
+        assert_eq!(theme.digit(0), default.digit(0));
+        assert_eq!(theme.digit(2), default.digit(2));
+    }
+
+    #[test]
+    fn loads_themes_from_directory() {
+        let dir = env::temp_dir().join(format!("themes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let atlas = include_bytes!("../template/themes/pixel.png");
+        fs::write(dir.join("neon.png"), atlas).unwrap();
+        fs::write(
+            dir.join("neon.json"),
+            include_str!("../template/themes/pixel.json"),
+        )
+        .unwrap();
+        fs::write(dir.join("missing.png"), atlas).unwrap();
+        fs::write(dir.join("in valid.png"), atlas).unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let themes = load_directory(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(themes.len(), 1);
+        assert_eq!(themes[0].name(), "neon");
+        assert_eq!(themes[0].digit(4), get("pixel").unwrap().digit(4));
+        assert!(load_directory(&dir).is_empty());
+    }
+
+    #[test]
+    fn draws_digits_from_font() {
+        let font = get(FONT).unwrap();
+        let (width, height) = text::measure("7", FONT_STYLE.size);
+        assert_eq!(font.digit(7).dimensions(), (width + 8, height + 8));
+        assert_ne!(font.digit(1), font.digit(7));
+        assert_eq!(font.number_size("77"), (2 * (width + 8), height + 8));
+        assert!(!font.sprite(0).is_empty());
+    }
+}